@@ -19,15 +19,34 @@ use cargo::util::{important_paths, CargoResult};
 #[macro_use]
 extern crate dlopen_derive;
 use dlopen::wrapper::{Container, WrapperApi};
+use fmi2::model_description::{ModelDescription, ModelError, ParseError};
 
 
 #[derive(WrapperApi)]
 struct PluginApi {
-    get_model_description: extern fn() -> String,
+    get_model_description: extern fn() -> Result<String, ModelError>,
     get_model_name: extern fn() -> &'static str,
 }
 
-fn main() {
+/// Why the model built at `path` can't be packaged into an FMU.
+#[derive(thiserror::Error)]
+enum CreateFmuError {
+    #[error("{}: the model has no valid model description: {source}", path.display())]
+    InvalidModel { path: PathBuf, source: ModelError },
+    #[error("{}: the model exports an invalid modelDescription.xml: {source}", path.display())]
+    InvalidModelDescription { path: PathBuf, source: ParseError },
+    #[error("{}: the model description has neither CoSimulation nor ModelExchange", path.display())]
+    NoModelIdentifier { path: PathBuf },
+}
+
+// `main` prints the error it returns with Debug, which should be the message
+impl std::fmt::Debug for CreateFmuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+fn main() -> Result<(), CreateFmuError> {
     let package_name = env!("CARGO_PKG_NAME");
 
     let version = format!(
@@ -59,7 +78,20 @@ fn main() {
 
     // See https://github.com/zicklag/rust-tutorials/blob/master/book/rust-plugins.md
     let plugin_api_wrapper: Container<PluginApi> = unsafe { Container::load(&dylib_path) }.unwrap();
-    let model_description_xml = plugin_api_wrapper.get_model_description();
+    let model_description_xml = plugin_api_wrapper
+        .get_model_description()
+        .map_err(|source| CreateFmuError::InvalidModel { path: dylib_path.clone(), source })?;
+
+    // Check the model description before it goes into the FMU. The binaries are named after the
+    // model identifier, which is what importers load them by.
+    let model_description = ModelDescription::from_xml(&model_description_xml)
+        .map_err(|source| CreateFmuError::InvalidModelDescription { path: dylib_path.clone(), source })?;
+    let model_identifier = model_description
+        .co_simulation
+        .as_ref()
+        .map(|cs| cs.model_identifier.clone())
+        .or_else(|| model_description.model_exchange.as_ref().map(|me| me.model_identifier.clone()))
+        .ok_or_else(|| CreateFmuError::NoModelIdentifier { path: dylib_path.clone() })?;
     
     let model_name = plugin_api_wrapper.get_model_name();
     let path = Path::new(&manifest_path);
@@ -84,7 +116,7 @@ fn main() {
     create_dir_all(bin_dir_str).expect("Could not create directory binaries");
 
     let dylib_file_ext = Path::new(&dylib_path).extension().and_then(OsStr::to_str).unwrap();
-    std::fs::copy(&dylib_path, bin_dir.join(format!("{}.{}", model_name, dylib_file_ext))).expect("Could not copy modelDescription.xml");
+    std::fs::copy(&dylib_path, bin_dir.join(format!("{}.{}", model_identifier, dylib_file_ext))).expect("Could not copy modelDescription.xml");
 
     let model_description_file = path.join("modelDescription.xml");
     let model_description_file = model_description_file.to_str().unwrap();
@@ -99,7 +131,7 @@ fn main() {
     zip_writer.start_file("modelDescription.xml", options).unwrap();
     zip_writer.write(model_description_xml.as_str().as_bytes()).unwrap();
     zip_writer.add_directory("binaries/darwin64", options).unwrap();
-    let bin_filename = format!("{}.{}", model_name, dylib_file_ext);
+    let bin_filename = format!("{}.{}", model_identifier, dylib_file_ext);
     let bin_file = std::fs::read(dylib_path).unwrap();
     println!("{}", bin_filename);
 
@@ -107,4 +139,5 @@ fn main() {
     zip_writer.start_file(format!("binaries/darwin64/{}", bin_filename), options).unwrap();
     zip_writer.write(bin_file.as_slice()).unwrap();
 
+    Ok(())
 }   
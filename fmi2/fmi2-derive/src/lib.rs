@@ -1,107 +1,205 @@
 extern crate proc_macro;
-extern crate quick_xml;
-extern crate handlebars;
-extern crate serde_json;
-extern crate serde;
-use serde::{Serialize, Deserialize};
-
-use handlebars::Handlebars;
-
-use quick_xml::events::{BytesCData, BytesDecl, BytesEnd, BytesStart, Event};
-use quick_xml::writer::Writer;
-use std::collections::HashSet;
-use std::io::Cursor;
+
 use std::str;
 
 use darling::{
     ast::{self},
-    FromDeriveInput, FromField, FromMeta, ToTokens,
+    FromDeriveInput, FromField, FromMeta, FromVariant,
 };
 
 use proc_macro2::{self, TokenStream};
-use quote::quote;
+use quote::{quote, quote_spanned};
 use syn::parse_macro_input;
 
 /// A speaking volume. Deriving `FromMeta` will cause this to be usable
 /// as a string value for a meta-item key.
-#[derive(Debug, Clone, Copy, FromMeta, PartialEq)]
+#[derive(Debug, Clone, Copy, Default, FromMeta, PartialEq)]
 #[darling(default)]
 enum Causality {
+    #[default]
     Ignore,
     Output,
     Input,
     Parameter,
+    #[darling(rename = "calculatedParameter")]
+    CalculatedParameter,
+    Local,
     Independent,
 }
 
-impl ToString for Causality {
-    fn to_string(&self) -> String {
+impl Causality {
+    fn name(&self) -> &'static str {
         match self {
             Causality::Ignore => "ignore",
             Causality::Output => "output",
             Causality::Input => "input",
             Causality::Parameter => "parameter",
+            Causality::CalculatedParameter => "calculatedParameter",
+            Causality::Local => "local",
             Causality::Independent => "independent",
         }
-        .to_string()
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord, FromMeta)]
-#[darling(default)]
-struct VarRefId(u64);
+#[derive(Debug, Clone, Copy, FromMeta, PartialEq)]
+enum Variability {
+    Constant,
+    Fixed,
+    Tunable,
+    Discrete,
+    Continuous,
+}
 
-#[derive(Debug, Clone, FromMeta)]
-#[darling(default)]
-struct Description(String);
+#[derive(Debug, Clone, Copy, FromMeta, PartialEq)]
+enum Initial {
+    Exact,
+    Approx,
+    Calculated,
+}
 
-#[derive(Debug, Clone, FromMeta)]
-#[darling(default)]
-struct GUID(String);
+impl Variability {
+    fn name(&self) -> &'static str {
+        match self {
+            Variability::Constant => "constant",
+            Variability::Fixed => "fixed",
+            Variability::Tunable => "tunable",
+            Variability::Discrete => "discrete",
+            Variability::Continuous => "continuous",
+        }
+    }
+}
 
-#[derive(Debug, Clone, FromMeta)]
-#[darling(default)]
-struct Unit(String);
+impl Initial {
+    fn name(&self) -> &'static str {
+        match self {
+            Initial::Exact => "exact",
+            Initial::Approx => "approx",
+            Initial::Calculated => "calculated",
+        }
+    }
+}
 
+impl Causality {
+    /// The initial attributes the FMI 2.0 standard allows for a variable, the first one is the
+    /// default. `None` if the variability isn't allowed with this causality, empty if the
+    /// variable has no initial attribute.
+    fn allowed_initials(&self, variability: Variability) -> Option<&'static [Initial]> {
+        use Variability::*;
+        match (self, variability) {
+            (Causality::Parameter, Fixed | Tunable) => Some(&[Initial::Exact]),
+            (Causality::Output | Causality::Local, Constant) => Some(&[Initial::Exact]),
+            (Causality::CalculatedParameter | Causality::Local, Fixed | Tunable) => {
+                Some(&[Initial::Calculated, Initial::Approx])
+            }
+            (Causality::Output | Causality::Local, Discrete | Continuous) => {
+                Some(&[Initial::Calculated, Initial::Exact, Initial::Approx])
+            }
+            (Causality::Input, Discrete | Continuous) | (Causality::Independent, Continuous) => Some(&[]),
+            _ => None,
+        }
+    }
+}
 
-#[derive(Debug, Clone, FromMeta)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Eq, Ord, FromMeta)]
 #[darling(default)]
-struct ExportFMI(bool);
+struct VarRefId(u64);
 
-impl Default for ExportFMI {
-    fn default() -> Self {
-        ExportFMI(false)
+/// A `min` or `max`, which can be an integer or a negative number.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bound(f64);
+
+impl FromMeta for Bound {
+    fn from_expr(expr: &syn::Expr) -> darling::Result<Self> {
+        match expr {
+            syn::Expr::Unary(syn::ExprUnary { op: syn::UnOp::Neg(_), expr, .. }) => Bound::from_expr(expr).map(|b| Bound(-b.0)),
+            syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(lit), .. }) => Ok(Bound(lit.base10_parse()?)),
+            syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Float(lit), .. }) => Ok(Bound(lit.base10_parse()?)),
+            _ => Err(darling::Error::unexpected_expr_type(expr)),
+        }
     }
 }
 
-impl Default for Causality {
-    fn default() -> Self {
-        Causality::Ignore
-    }
+/// The `dependenciesKind` of a dependency, see `fmi2::model_description::DependencyKind`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DependencyKind {
+    Dependent,
+    Constant,
+    Fixed,
+    Tunable,
+    Discrete,
 }
 
-impl Default for GUID {
-    fn default() -> Self {
-        GUID(format!("{{{}}}", uuid::Uuid::new_v4().to_string()))
-    }
-}
+/// `depends_on = ["u: fixed", "x"]`, the inputs, continuous states and independent variable an
+/// output or derivative depends on, each with an optional kind.
+#[derive(Debug, Clone, PartialEq)]
+struct Dependencies(Vec<(String, DependencyKind)>);
 
-impl Default for VarRefId {
-    fn default() -> VarRefId {
-        VarRefId(0)
+impl FromMeta for Dependencies {
+    fn from_expr(expr: &syn::Expr) -> darling::Result<Self> {
+        let elements = match expr {
+            syn::Expr::Array(array) => &array.elems,
+            _ => return Err(darling::Error::unexpected_expr_type(expr)),
+        };
+
+        let mut dependencies = Vec::new();
+        for element in elements {
+            let text = match element {
+                syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(lit), .. }) => lit.value(),
+                _ => return Err(darling::Error::unexpected_expr_type(element)),
+            };
+            let (name, kind) = match text.split_once(':') {
+                Some((name, kind)) => (name.trim(), kind.trim()),
+                None => (text.trim(), "dependent"),
+            };
+            let kind = match kind {
+                "dependent" => DependencyKind::Dependent,
+                "constant" => DependencyKind::Constant,
+                "fixed" => DependencyKind::Fixed,
+                "tunable" => DependencyKind::Tunable,
+                "discrete" => DependencyKind::Discrete,
+                _ => return Err(darling::Error::unknown_value(kind).with_span(element)),
+            };
+            dependencies.push((name.to_string(), kind));
+        }
+        Ok(Dependencies(dependencies))
     }
 }
 
-impl Default for Description {
-    fn default() -> Description {
-        Description("".to_string())
-    }
+/// What the setters do with values outside of `min` and `max`, see `fmi2::RangePolicy`.
+#[derive(Debug, Clone, Copy, Default, FromMeta, PartialEq)]
+#[darling(default)]
+enum RangePolicy {
+    #[default]
+    Reject,
+    Warn,
 }
 
-impl Default for Unit {
-    fn default() -> Unit {
-        Unit("".to_string())
-    }
+#[derive(Debug, Clone, Default, FromMeta)]
+#[darling(default)]
+struct Description(String);
+
+#[derive(Debug, Clone, FromMeta)]
+struct Guid(String);
+
+#[derive(Debug, Clone, Default, FromMeta)]
+#[darling(default)]
+struct Unit(String);
+
+
+#[derive(Debug, Clone, Default, FromMeta)]
+#[darling(default)]
+struct ExportFMI(bool);
+
+/// How `fmi2GetDirectionalDerivative` is implemented, if at all.
+#[derive(Debug, Clone, Copy, Default, FromMeta, PartialEq)]
+#[darling(default)]
+enum DirectionalDerivatives {
+    #[default]
+    None,
+    /// Through the model's `fmi2::DirectionalDerivative` impl
+    Analytic,
+    /// With `fmi2::finite_difference_directional_derivative`, for Model Exchange models
+    FiniteDifference,
 }
 
 /// Support parsing from a full derive input. Unlike FromMeta, this isn't
@@ -115,10 +213,6 @@ struct FmiModelStructReceiver {
     /// The struct ident.
     ident: syn::Ident,
 
-    /// The type's generics. You'll need these any time your trait is expected
-    /// to work with types that declare generics.
-    generics: syn::Generics,
-
     /// Receives the body of the struct or enum. We don't care about
     /// struct fields because we previously told darling we only accept structs.
     data: ast::Data<(), FmiVariableReceiver>,
@@ -126,11 +220,72 @@ struct FmiModelStructReceiver {
     #[darling(default)]
     description: Description,
 
+    /// Derived from the interface of the model if not given, see
+    /// `fmi2::model_description::interface_guid`.
     #[darling(default)]
-    guid: GUID,
+    guid: Option<Guid>,
 
     #[darling(default)]
     export: ExportFMI,
+
+    /// Number of event indicators of a Model Exchange model, see `fmi2::EventModel`.
+    #[darling(default)]
+    event_indicators: usize,
+
+    #[darling(default)]
+    directional_derivatives: DirectionalDerivatives,
+
+    /// Relative perturbation for `directional_derivatives = "finite_difference"`, scaled by the
+    /// nominal values of the knowns.
+    #[darling(default)]
+    finite_difference_step: Option<f64>,
+
+    #[darling(default)]
+    range_policy: RangePolicy,
+
+    /// The value reference automatic assignment starts at. The ones below it are reserved for
+    /// variables with an `id`, so adding those doesn't move the others.
+    #[darling(default)]
+    vr_base: Option<u64>,
+
+    /// A `&[fmi2::units::Unit]` the units of the variables are looked up in before
+    /// `fmi2::units::SI_UNITS`.
+    #[darling(default)]
+    units: Option<syn::Path>,
+
+    /// Capability flags of `<CoSimulation>`. The ones that need more than the default exports
+    /// require the model to implement the matching trait, e.g. `fmi2::InputDerivatives`.
+    #[darling(default)]
+    can_handle_variable_communication_step_size: bool,
+    #[darling(default)]
+    can_interpolate_inputs: bool,
+    #[darling(default)]
+    max_output_derivative_order: u32,
+    #[darling(default)]
+    can_run_asynchronuously: bool,
+    #[darling(default)]
+    can_be_instantiated_only_once_per_process: bool,
+    #[darling(default)]
+    can_not_use_memory_management_functions: bool,
+    #[darling(default)]
+    needs_execution_tool: bool,
+
+    /// Detected from the traits the model implements if not given, `false` turns the FMU state
+    /// functions off for a model that would support them.
+    #[darling(default)]
+    can_get_and_set_fmu_state: Option<bool>,
+    #[darling(default)]
+    can_serialize_fmu_state: Option<bool>,
+
+    /// The `<DefaultExperiment>`, left out if none of these are given.
+    #[darling(default)]
+    start_time: Option<f64>,
+    #[darling(default)]
+    stop_time: Option<f64>,
+    #[darling(default)]
+    tolerance: Option<f64>,
+    #[darling(default)]
+    step_size: Option<f64>,
 }
 
 #[derive(Debug, FromField)]
@@ -153,338 +308,1310 @@ struct FmiVariableReceiver {
     #[darling(default)]
     id: Option<VarRefId>,
 
+    /// Defaults to fixed for parameters, continuous for other Real variables and discrete for
+    /// everything else.
+    #[darling(default)]
+    variability: Option<Variability>,
+
+    /// Defaults to the one the FMI 2.0 standard gives for the causality and variability.
+    #[darling(default)]
+    initial: Option<Initial>,
+
     #[darling(default)]
     description: Description,
 
     #[darling(default)]
     unit: Unit,
 
-    starting_value: Option<f64>,
+    /// The display unit the variable is shown in, one of the display units of `unit`.
+    #[darling(default)]
+    display_unit: Option<String>,
+
+    /// The start value of a Real or Integer variable, set on the model after it is instantiated
+    /// in place of the one from `Default`.
+    #[darling(default)]
+    starting_value: Option<Bound>,
+
+    /// Marks this field as the derivative of the named field, which makes that field a
+    /// continuous state for Model Exchange.
+    #[darling(default)]
+    derivative: Option<String>,
+
+    /// Nominal value of a Real variable, used to scale continuous states and finite differences.
+    #[darling(default)]
+    nominal: Option<f64>,
+
+    /// The variables an output or derivative depends on, for the `<ModelStructure>`. Variables
+    /// of flattened fields are named `field.variable`.
+    #[darling(default)]
+    depends_on: Option<Dependencies>,
+
+    /// Range of a Real or Integer variable, enforced by the setters according to the
+    /// `range_policy` of the model.
+    #[darling(default)]
+    min: Option<Bound>,
+    #[darling(default)]
+    max: Option<Bound>,
+
+    /// The field is a struct that derives `FmiModelStructDerive` itself, its variables become
+    /// variables of this model named `field.variable`. An `id` sets the value reference its
+    /// variables start at.
+    #[darling(default)]
+    flatten: bool,
 }
 
-impl ToTokens for FmiModelStructReceiver {
-    fn to_tokens(&self, tokens: &mut TokenStream) {}
+/// A `#[fmi_variable(flatten)]` field.
+struct Child {
+    ident: syn::Ident,
+    ty: syn::Type,
+    /// The value reference the variables of the child start at, only known at runtime since it
+    /// depends on the children before it.
+    offset: TokenStream,
 }
 
-#[proc_macro_derive(FmiModelStructDerive, attributes(fmi_model, fmi_variable))]
-pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let input = parse_macro_input!(input);
-    let fmi_model = FmiModelStructReceiver::from_derive_input(&input).expect("Wrong options");
+/// The FMI type a field is exported as.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FmiType {
+    Real,
+    Integer,
+    Boolean,
+    String,
+    /// Any other type, which has to implement `fmi2::FmiEnumeration`
+    Enumeration,
+}
 
-    let model_name = fmi_model.ident;
+impl FmiType {
+    /// Map a Rust type to its FMI type. Only the last segment of the path counts, so qualified
+    /// paths like `core::primitive::f64` work too. Types without generics that aren't known
+    /// are taken to be enumerations.
+    fn of(ty: &syn::Type) -> Option<FmiType> {
+        let path = match ty {
+            syn::Type::Group(t) => return FmiType::of(&t.elem),
+            syn::Type::Paren(t) => return FmiType::of(&t.elem),
+            syn::Type::Path(t) if t.qself.is_none() => &t.path,
+            _ => return None,
+        };
 
-    // Get all fields
-    let mut fields = fmi_model
-        .data
-        .take_struct()
-        .expect("Should never be enum")
-        .fields;
+        let segment = path.segments.last()?;
+        if !segment.arguments.is_empty() {
+            return None;
+        }
 
-    let mut fields = fields
-        .iter_mut()
-        .filter(|x| x.causality != Causality::Ignore)
-        .collect::<Vec<_>>();
+        match segment.ident.to_string().as_str() {
+            "f64" | "f32" => Some(FmiType::Real),
+            "i8" | "i16" | "i32" | "i64" | "isize" | "u8" | "u16" | "u32" | "u64" | "usize" => Some(FmiType::Integer),
+            "bool" => Some(FmiType::Boolean),
+            "String" => Some(FmiType::String),
+            _ => Some(FmiType::Enumeration),
+        }
+    }
 
-    let enum_name: String = format!("{}Variables", model_name);
-    let value_reference_enum = syn::Ident::new(&enum_name, model_name.span());
+    /// The element name in the model description.
+    fn name(&self) -> &'static str {
+        match self {
+            FmiType::Real => "Real",
+            FmiType::Integer => "Integer",
+            FmiType::Boolean => "Boolean",
+            FmiType::String => "String",
+            FmiType::Enumeration => "Enumeration",
+        }
+    }
+}
 
-    // // Assign all value reference ids by finding the max id that occurs and making sure all enum fields are above that value.
-    let max_value_ref_defined = fields.iter().max_by(|x, y| {
-        x.id.unwrap_or(VarRefId(0))
-            .cmp(&y.id.unwrap_or(VarRefId(0)))
-    });
+impl FmiVariableReceiver {
+    /// The length of an array field, `None` for scalar fields. `check_type` reports array
+    /// lengths that aren't integer literals.
+    fn array_len(&self) -> Option<usize> {
+        match &self.ty {
+            syn::Type::Array(array) => match &array.len {
+                syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(len), .. }) => len.base10_parse().ok(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
 
-    let mut last_value_ref = match max_value_ref_defined {
-        Some(f) => f.id.unwrap_or(VarRefId(0)).0 + 1,
-        None => 0,
-    };
+    /// The type of the scalar variables of this field, the element type for arrays.
+    fn scalar_type(&self) -> &syn::Type {
+        match &self.ty {
+            syn::Type::Array(array) => &array.elem,
+            ty => ty,
+        }
+    }
 
-    for field in fields.iter_mut() {
-        let var_ref_id = field.id;
-        match var_ref_id {
-            Some(VarRefId(id)) => (),
-            None => {
-                field.id = Some(VarRefId(last_value_ref));
-                last_value_ref += 1;
+    /// The FMI type of the field. `check_type` reports the types that have none.
+    fn fmi_type(&self) -> FmiType {
+        FmiType::of(self.scalar_type()).unwrap_or(FmiType::Enumeration)
+    }
+
+    /// Report array lengths that aren't integer literals and types that aren't FMI types.
+    fn check_type(&self, errors: &mut darling::error::Accumulator) {
+        let name = self.ident.as_ref().unwrap();
+        if let syn::Type::Array(array) = &self.ty {
+            if self.array_len().is_none() {
+                errors.push(darling::Error::custom("the length of an array field must be an integer literal").with_span(&array.len));
             }
         }
+        let ty = self.scalar_type();
+        if FmiType::of(ty).is_none() {
+            errors.push(darling::Error::custom(format!("unsupported type {}", quote!(#ty))).with_span(name));
+        }
     }
 
-    let enum_fields: Vec<TokenStream> = fields
-        .iter()
-        .map(|f| {
-            let name = f.ident.as_ref().unwrap();
-            quote! { #name }
-        })
-        .collect::<Vec<_>>();
+    /// The value references of the scalar variables of this field, after they are assigned.
+    fn value_references(&self) -> std::ops::Range<u64> {
+        let first = self.id.unwrap().0;
+        first..first + self.array_len().unwrap_or(1) as u64
+    }
 
-    let field_vrs: Vec<TokenStream> = fields
-        .iter()
-        .map(|f: &&mut FmiVariableReceiver| match f.id {
-            Some(VarRefId(id)) => quote! { #id },
-            None => quote! { 0 },
-        })
-        .collect::<Vec<_>>();
+    /// The names of the scalar variables of this field, array elements are numbered from 1
+    /// following the structured naming convention.
+    fn scalar_names(&self) -> Vec<String> {
+        let name = self.ident.as_ref().unwrap().to_string();
+        match self.array_len() {
+            Some(len) => (1..=len).map(|i| format!("{}[{}]", name, i)).collect(),
+            None => vec![name],
+        }
+    }
 
-    let real_fields_idents = fields
-        .iter()
-        .filter(|x| x.ty == syn::parse_str::<syn::Type>("f64").unwrap())
-        .map(|f| {
-            let name = f.ident.as_ref().unwrap();
-            quote! { #name }
-        })
-        .collect::<Vec<_>>();
+    /// The scalar variable with the given `value_reference` in generated accessors, relative to
+    /// `self`.
+    fn access(&self) -> TokenStream {
+        let name = self.ident.as_ref().unwrap();
+        match self.array_len() {
+            Some(_) => {
+                let first = self.id.unwrap().0;
+                quote! { #name[(value_reference - #first) as usize] }
+            }
+            None => quote! { #name },
+        }
+    }
 
-    let bool_fields_idents: Vec<TokenStream> = fields
-        .iter()
-        .filter(|x| x.ty == syn::parse_str::<syn::Type>("bool").unwrap())
-        .map(|f| {
-            let name = f.ident.as_ref().unwrap();
-            quote! { #name }
-        })
-        .collect::<Vec<_>>();
+    /// The name of the scalar variable with the given `value_reference`, for error messages.
+    fn name_expr(&self) -> TokenStream {
+        let name = self.ident.as_ref().unwrap().to_string();
+        match self.array_len() {
+            Some(_) => {
+                let first = self.id.unwrap().0;
+                quote! { format_args!("{}[{}]", #name, value_reference - #first + 1) }
+            }
+            None => quote! { #name },
+        }
+    }
 
-    let integer_fields_idents: Vec<TokenStream> = fields
-        .iter()
-        .filter(|x| x.ty == syn::parse_str::<syn::Type>("i64").unwrap())
-        .map(|f| {
-            let name = f.ident.as_ref().unwrap();
-            quote! { #name }
+    fn variability(&self) -> Variability {
+        self.variability.unwrap_or(match (self.causality, self.fmi_type()) {
+            (Causality::Parameter | Causality::CalculatedParameter, _) => Variability::Fixed,
+            (_, FmiType::Real) => Variability::Continuous,
+            _ => Variability::Discrete,
         })
-        .collect::<Vec<_>>();
-
-    // Create XML writer code
-    let mut writer = Writer::new_with_indent(Cursor::new(Vec::<u8>::new()), ' ' as u8, 4);
-
-    // Write XML Header
-    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("ISO-8859-1"), None)));
-
-    // Create the fmiModelDescription header. For example:
-    //     <fmiModelDescription
-    //   fmiVersion               = "2.0"
-    //   modelName                = "Sine"
-    //   guid                     = "{21d9f232-b090-4c79-933f-33da939b5934}"
-    //   description              = "Model Sine Wave"
-    //   variableNamingConvention = "structured"
-    //   numberOfEventIndicators  = "0">
-    writer.write_indent();
-
-    let mut fmi_model_description = BytesStart::new("fmiModelDescription");
-    // copy existing attributes, adds a new my-key="some value" attribute
-    fmi_model_description.push_attribute(("fmiVersion", "2.0"));
-    fmi_model_description.push_attribute(("modelName", model_name.to_string().as_str()));
-    fmi_model_description.push_attribute(("guid", fmi_model.guid.0.as_str()));
-    fmi_model_description.push_attribute(("description", fmi_model.description.0.as_str()));
-    // writes the event to the writer
-    writer.write_event(Event::Start(fmi_model_description));
-
-    writer.write_indent();
-
-    // TODO(cw): Add all the required elements, and make them configurable using attributes
-    let mut cosimulation_elements = BytesStart::new("CoSimulation");
-    // copy existing attributes, adds a new my-key="some value" attribute
-    cosimulation_elements.push_attribute(("modelIdentifier", model_name.to_string().as_str()));
-    cosimulation_elements.push_attribute(("canHandleVariableCommunicationStepSize", "false"));
-    cosimulation_elements.push_attribute(("canGetAndSetFMUstate", "true"));
-    cosimulation_elements.push_attribute(("canSerializeFMUstate", "false"));
-    cosimulation_elements.push_attribute(("providesDirectionalDerivative", "false"));
-    cosimulation_elements.push_attribute(("canInterpolateInputs", "false"));
-    writer.write_event(Event::Start(cosimulation_elements));
-    let cosimulation_elements = BytesEnd::new("CoSimulation");
-    writer.write_event(Event::End(cosimulation_elements));
-
-    writer.write_indent();
-
-    writer.write_event(Event::Start(BytesStart::new("UnitDefinitions")));
-
-    // get all unique units and then add each unit to the model definition
-    let unique_units = fields
-        .iter()
-        .map(|field| &field.unit.0)
-        .collect::<HashSet<_>>()
-        .into_iter();
-    unique_units.for_each(|unit| {
-        let mut elem = BytesStart::new("Unit");
-        elem.push_attribute(("name", unit.as_str()));
-        writer.write_event(Event::Empty(elem));
-    });
-
-    writer.write_event(Event::End(BytesEnd::new("UnitDefinitions")));
-    writer.write_indent();
+    }
 
-    // Add default experiment, but don't make it configurable
-    // TODO(cw): Make this configurable through an attribute
-    let mut default_experiment = BytesStart::new("DefaultExperiment");
-    default_experiment.push_attribute(("startTime", "0.0"));
-    default_experiment.push_attribute(("stopTime", "1.0"));
-    default_experiment.push_attribute(("tolerance", "0.0001"));
-    default_experiment.push_attribute(("stepSize", "0.01"));
-    writer.write_event(Event::Empty(default_experiment));
+    fn initial(&self) -> Option<Initial> {
+        match self.causality.allowed_initials(self.variability()) {
+            Some([default, ..]) => Some(self.initial.unwrap_or(*default)),
+            _ => None,
+        }
+    }
 
-    writer.write_indent();
+    /// Report combinations of causality, variability and initial the standard doesn't allow.
+    fn check_attributes(&self, errors: &mut darling::error::Accumulator) {
+        let name = self.ident.as_ref().unwrap();
+        let variability = self.variability();
+        let Some(allowed) = self.causality.allowed_initials(variability) else {
+            errors.push(
+                darling::Error::custom(format!("causality = \"{}\" can't have variability = \"{}\"", self.causality.name(), variability.name()))
+                    .with_span(name),
+            );
+            return;
+        };
+        if variability == Variability::Continuous && self.fmi_type() != FmiType::Real {
+            errors.push(darling::Error::custom("only Real variables can be continuous").with_span(name));
+        }
+        if let Some(initial) = self.initial {
+            if !allowed.contains(&initial) {
+                errors.push(
+                    darling::Error::custom(format!(
+                        "causality = \"{}\" with variability = \"{}\" can't have initial = \"{}\"",
+                        self.causality.name(),
+                        variability.name(),
+                        initial.name()
+                    ))
+                    .with_span(name),
+                );
+            }
+        }
+    }
 
-    // Populate Model Variables
-    writer.write_event(Event::Start(BytesStart::new("ModelVariables")));
+    // The exports check the attributes at runtime to decide which variables may be set in which
+    // state, so they are the same ones that go into the model description.
+    fn causality_tokens(&self) -> TokenStream {
+        match self.causality {
+            Causality::Output => quote! { fmi2::Causality::Output },
+            Causality::Input => quote! { fmi2::Causality::Input },
+            Causality::Parameter => quote! { fmi2::Causality::Parameter },
+            Causality::CalculatedParameter => quote! { fmi2::Causality::CalculatedParameter },
+            Causality::Independent => quote! { fmi2::Causality::Independent },
+            Causality::Local | Causality::Ignore => quote! { fmi2::Causality::Local },
+        }
+    }
 
-    for field in fields.iter() {
-        let mut event = BytesStart::new("ScalarVariable");
-        event.push_attribute(("name", field.ident.as_ref().unwrap().to_string().as_str()));
-        event.push_attribute(("valueReference", field.id.unwrap().0.to_string().as_str()));
-        event.push_attribute(("description", field.description.0.as_str()));
-        event.push_attribute(("causality", field.causality.to_string().as_str()));
-        if (field.causality == Causality::Parameter) {
-            event.push_attribute(("variability", "fixed"));
-        } else {
-            event.push_attribute(("variability", "continuous"));
-        }
-        writer.write_event(Event::Start(event));
-
-        let type_string = match &field.ty {
-            syn::Type::Path(t) => {
-                if t.path.is_ident("f64") {
-                    "Real"
-                } else if t.path.is_ident("i64") {
-                    "Integer"
-                } else if t.path.is_ident("bool") {
-                    "Boolean"
-                } else {
-                    panic!("Unsupported type");
-                }
-            }
-            _ => {
-                panic!("Unsupported type");
-            }
-        };
+    fn variability_tokens(&self) -> TokenStream {
+        match self.variability() {
+            Variability::Constant => quote! { fmi2::Variability::Constant },
+            Variability::Fixed => quote! { fmi2::Variability::Fixed },
+            Variability::Tunable => quote! { fmi2::Variability::Tunable },
+            Variability::Discrete => quote! { fmi2::Variability::Discrete },
+            Variability::Continuous => quote! { fmi2::Variability::Continuous },
+        }
+    }
 
-        let mut event = BytesStart::new(type_string);
+    fn initial_tokens(&self) -> TokenStream {
+        match self.initial() {
+            Some(Initial::Exact) => quote! { Some(fmi2::Initial::Exact) },
+            Some(Initial::Approx) => quote! { Some(fmi2::Initial::Approx) },
+            Some(Initial::Calculated) => quote! { Some(fmi2::Initial::Calculated) },
+            None => quote! { None },
+        }
+    }
 
-        if type_string == "Real" {
-            event.push_attribute(("unit", field.unit.0.as_str()));
+    /// Check `value` against `min` and `max` before `assign` sets the variable.
+    fn range_checked(&self, policy: &TokenStream, assign: TokenStream) -> TokenStream {
+        if self.min.is_none() && self.max.is_none() {
+            return quote! { { #assign; Ok(()) } };
         }
 
-        // TODO: Fix start value settings
-        if field.causality == Causality::Parameter || field.causality == Causality::Input {
-            let mut start_value_tag = String::from("{{");
-            start_value_tag.push_str(field.ident.as_ref().unwrap().to_string().as_str());
-            start_value_tag.push_str("}}");
-            event.push_attribute(("start", start_value_tag.as_str()));
+        let name = self.name_expr();
+        let bound = |bound: Option<Bound>| match bound {
+            Some(Bound(value)) => quote! { Some(#value) },
+            None => quote! { None },
+        };
+        let (min, max) = (bound(self.min), bound(self.max));
+        quote! {
+            {
+                let warning = #policy.check(#name, value as f64, #min, #max)?;
+                #assign;
+                warning.map_or(Ok(()), Err)
+            }
         }
+    }
 
-        writer.write_event(Event::Empty(event)).expect("could not write event");
-        writer.write_event(Event::End(BytesEnd::new("ScalarVariable"))).expect("could not write scalar variable");
-        writer.write_indent().expect("Could not write indent");
+    /// Whether the model description gives a start value, which the standard requires for
+    /// inputs and variables with an exact or approx initial value.
+    fn has_start(&self) -> bool {
+        self.causality == Causality::Input || matches!(self.initial(), Some(Initial::Exact | Initial::Approx))
     }
+}
 
-    // TODO(cw): Support non scalar variables
-    writer.write_event(Event::End(BytesEnd::new("ModelVariables")));
+/// Forward a `value_reference` that belongs to a flattened child to it. `call` gets the field and
+/// type of the child, with `value_reference` relative to the child.
+fn dispatch_to_children(children: &[Child], call: impl Fn(&syn::Ident, &syn::Type) -> TokenStream) -> TokenStream {
+    let dispatch = children.iter().map(|child| {
+        let (offset, ty) = (&child.offset, &child.ty);
+        let call = call(&child.ident, ty);
+        quote! {
+            if let Some(value_reference) = value_reference
+                .checked_sub(#offset)
+                .filter(|vr| *vr < <#ty as fmi2::FmiModelStructDerive>::value_reference_span())
+            {
+                return #call;
+            }
+        }
+    });
+    quote! { #(#dispatch)* }
+}
 
-    writer.write_event(Event::Start(BytesStart::new("ModelStructure")));
+#[proc_macro_derive(FmiModelStructDerive, attributes(fmi_model, fmi_variable))]
+pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input);
+    let fmi_model = match FmiModelStructReceiver::from_derive_input(&input) {
+        Ok(fmi_model) => fmi_model,
+        Err(e) => return e.write_errors().into(),
+    };
 
-    writer.write_event(Event::Start(BytesStart::new("Outputs")));
+    let model_name = fmi_model.ident;
 
-    for (index, field) in fields.iter().enumerate() {
-        if field.causality == Causality::Output {
-            let mut event = BytesStart::new("Unknown");
-            event.push_attribute(("index", (index + 1).to_string().as_str()));
-            event.push_attribute(("dependencies", ""));
-            writer.write_event(Event::Empty(event));
+    // Get all fields
+    let mut fields = fmi_model
+        .data
+        .take_struct()
+        .expect("Should never be enum")
+        .fields;
+
+    // Derivatives are FMI variables even without an explicit causality
+    for field in fields.iter_mut() {
+        if field.derivative.is_some() && field.causality == Causality::Ignore {
+            field.causality = Causality::Local;
         }
     }
 
-    writer.write_event(Event::End(BytesEnd::new("Outputs")));
+    // The checks collect their errors, so all of them are reported at once
+    let mut errors = darling::Error::accumulator();
 
-    writer.write_event(Event::End(BytesEnd::new("ModelStructure")));
-
-    let mut fmi_model_description = BytesEnd::new("fmiModelDescription");
-    writer.write_event(Event::End(fmi_model_description));
+    for field in fields.iter().filter(|f| f.flatten) {
+        if field.causality != Causality::Ignore || matches!(field.ty, syn::Type::Array(_)) {
+            errors.push(darling::Error::custom("a flattened field is a struct without a causality").with_span(field.ident.as_ref().unwrap()));
+        }
+    }
+    let flattened: Vec<(syn::Ident, syn::Type, Option<VarRefId>)> = fields
+        .iter()
+        .filter(|f| f.flatten)
+        .map(|f| (f.ident.clone().unwrap(), f.ty.clone(), f.id))
+        .collect();
 
-    let result = writer.into_inner().into_inner();
-    let xml_model_description = String::from_utf8(result).expect("Found invalid UTF-8");
+    let mut fields = fields
+        .iter_mut()
+        .filter(|x| x.causality != Causality::Ignore && !x.flatten)
+        .collect::<Vec<_>>();
 
-    // Create the output code
-    let guid = fmi_model.guid.0;
-    let description = fmi_model.description.0;
-    let model_name_str = model_name.to_string();
-    let mut output = quote! {
-        // Create the value reference enum
-        #[derive(Copy, Clone)]
-        enum #value_reference_enum {
-            #(#enum_fields),*
-        }
+    for field in fields.iter() {
+        field.check_type(&mut errors);
+        field.check_attributes(&mut errors);
+    }
 
-        impl #value_reference_enum {
-            fn to_underlying(self: &Self) -> Option<u64> {
-                match self {
-                    #(#value_reference_enum::#enum_fields => Some(#field_vrs),)*
-                    _ => None,
+    // The continuous states, in the order of their derivatives. This order defines the
+    // state vector of the Model Exchange interface.
+    let state_derivatives: Vec<(usize, usize)> = fields
+        .iter()
+        .enumerate()
+        .filter_map(|(derivative_index, f)| {
+            let state_name = f.derivative.as_ref()?;
+            let Some(state_index) = fields.iter().position(|x| x.ident.as_ref().unwrap() == state_name) else {
+                errors.push(
+                    darling::Error::custom(format!("derivative = \"{}\": no fmi_variable named {}", state_name, state_name))
+                        .with_span(f.ident.as_ref().unwrap()),
+                );
+                return None;
+            };
+
+            for index in [derivative_index, state_index] {
+                let ident = fields[index].ident.as_ref().unwrap();
+                if fields[index].fmi_type() != FmiType::Real || matches!(fields[index].ty, syn::Type::Array(_)) {
+                    errors.push(darling::Error::custom("continuous states and their derivatives must be f64 or f32").with_span(ident));
                 }
-            }
-
-            fn from_underlying(x: u64) -> Option<Self> {
-                match x {
-                    #(#field_vrs => Some(#value_reference_enum::#enum_fields),)*
-                    _ => None,
+                if fields[index].variability() != Variability::Continuous {
+                    errors.push(darling::Error::custom("continuous states and their derivatives must be continuous").with_span(ident));
+                }
+            }
+            Some((state_index, derivative_index))
+        })
+        .collect();
+
+    let number_of_event_indicators = fmi_model.event_indicators;
+    let model_exchange = !state_derivatives.is_empty() || number_of_event_indicators > 0;
+
+    for field in fields.iter() {
+        let name = field.ident.as_ref().unwrap();
+        let mut error = |message: String| errors.push(darling::Error::custom(message).with_span(name));
+        if field.nominal.is_some() && field.fmi_type() != FmiType::Real {
+            error("only Real variables can have a nominal value".to_string());
+        }
+        if field.display_unit.is_some() && (field.fmi_type() != FmiType::Real || field.unit.0.is_empty()) {
+            error("only Real variables with a unit can have a display_unit".to_string());
+        }
+
+        let bounds = [field.min, field.max].into_iter().flatten();
+        match field.fmi_type() {
+            FmiType::Real => (),
+            FmiType::Integer if bounds.clone().all(|Bound(b)| b.fract() == 0.0 && b.abs() <= i32::MAX as f64) => (),
+            FmiType::Integer => error("min and max of an Integer variable must be fmi2Integer values".to_string()),
+            _ if bounds.clone().count() > 0 => error("only Real and Integer variables can have a min or max".to_string()),
+            _ => (),
+        }
+        if let (Some(Bound(min)), Some(Bound(max))) = (field.min, field.max) {
+            if min > max {
+                error(format!("min = {} is larger than max = {}", min, max));
+            }
+        }
+        if let Some(Bound(start)) = field.starting_value {
+            match field.fmi_type() {
+                FmiType::Real => (),
+                FmiType::Integer if start.fract() == 0.0 => (),
+                FmiType::Integer => error("the starting_value of an Integer variable must be an integer".to_string()),
+                _ => error("only Real and Integer variables can have a starting_value, the others start at their Default".to_string()),
+            }
+            if !field.has_start() {
+                error("only inputs and variables with initial = \"exact\" or \"approx\" have a start value".to_string());
+            }
+            let below = field.min.is_some_and(|Bound(min)| start < min);
+            let above = field.max.is_some_and(|Bound(max)| start > max);
+            if below || above {
+                error(format!("starting_value = {} is outside of min and max", start));
+            }
+        }
+    }
+
+    for field in fields.iter() {
+        let name = field.ident.as_ref().unwrap();
+        let mut error = |message: String| errors.push(darling::Error::custom(message).with_span(name));
+        let Some(Dependencies(dependencies)) = &field.depends_on else { continue };
+        if field.causality != Causality::Output && field.derivative.is_none() {
+            error("only outputs and derivatives can have depends_on".to_string());
+            continue;
+        }
+        for (dependency, _) in dependencies {
+            let root = dependency.split(['.', '[']).next().unwrap();
+            let is_field = fields.iter().any(|f| f.ident.as_ref().unwrap() == root);
+            let is_child = flattened.iter().any(|(ident, _, _)| ident == root);
+            let exists = if dependency.contains('.') { is_child } else { is_field };
+            if !exists {
+                error(format!("depends_on = \"{}\": no fmi_variable named {}", dependency, dependency));
+                continue;
+            }
+            // Dependencies on variables of flattened fields are checked when the model
+            // structure is built
+            if dependency.contains('.') {
+                continue;
+            }
+            let index = fields.iter().position(|f| f.ident.as_ref().unwrap() == root).unwrap();
+            let is_state = state_derivatives.iter().any(|(state_index, _)| *state_index == index);
+            if !matches!(fields[index].causality, Causality::Input | Causality::Independent) && !is_state {
+                error(format!(
+                    "depends_on = \"{}\": outputs and derivatives can only depend on inputs, continuous states and the independent variable",
+                    dependency
+                ));
+            }
+        }
+    }
+
+    // Outputs without depends_on depend on all knowns, which is correct but makes masters
+    // assume algebraic loops that aren't there. The use of a deprecated item warns about it at
+    // the field, there is no other way to emit a warning from a derive.
+    let dependency_warnings: Vec<TokenStream> = fields
+        .iter()
+        .filter(|f| f.causality == Causality::Output && f.depends_on.is_none())
+        .map(|f| {
+            let name = f.ident.as_ref().unwrap();
+            let note = format!(
+                "output `{}` declares no depends_on, so masters take it to depend on all inputs and states. Use `depends_on = []` if it depends on none of them",
+                name
+            );
+            quote_spanned! {name.span()=>
+                #[deprecated(note = #note)]
+                #[allow(non_upper_case_globals)]
+                const #name: () = ();
+                let _ = #name;
+            }
+        })
+        .collect();
+
+    let unit_registries = match &fmi_model.units {
+        Some(units) => quote! { &[#units, fmi2::units::SI_UNITS] },
+        None => quote! { &[fmi2::units::SI_UNITS] },
+    };
+
+    let range_policy = match fmi_model.range_policy {
+        RangePolicy::Reject => quote! { fmi2::RangePolicy::Reject },
+        RangePolicy::Warn => quote! { fmi2::RangePolicy::Warn },
+    };
+
+    let provides_directional_derivative = fmi_model.directional_derivatives != DirectionalDerivatives::None;
+
+    // Co-Simulation has nothing that recomputes the outputs after the inputs changed, short of
+    // taking a step
+    if fmi_model.directional_derivatives == DirectionalDerivatives::FiniteDifference && !model_exchange {
+        errors.push(
+            darling::Error::custom(
+                "directional_derivatives = \"finite_difference\" needs Model Exchange to evaluate the unknowns, use \"analytic\" with an fmi2::DirectionalDerivative impl",
+            )
+            .with_span(&model_name),
+        );
+    }
+    if fmi_model.can_run_asynchronuously {
+        errors.push(
+            darling::Error::custom("can_run_asynchronuously: the generated fmi2DoStep always completes the step before it returns")
+                .with_span(&model_name),
+        );
+    }
+    if fmi_model.can_serialize_fmu_state == Some(true) && fmi_model.can_get_and_set_fmu_state == Some(false) {
+        errors.push(
+            darling::Error::custom("can_serialize_fmu_state: serializing FMU states requires can_get_and_set_fmu_state")
+                .with_span(&model_name),
+        );
+    }
+    if let Err(errors) = errors.finish() {
+        return errors.write_errors().into();
+    }
+
+    // Capability flags that are claimed need the traits behind them, these make the model fail
+    // to compile if it doesn't implement them
+    let mut required_functions: Vec<TokenStream> = Vec::new();
+    if fmi_model.can_interpolate_inputs {
+        required_functions.push(quote! { <#model_name as fmi2::InputDerivatives>::set_real_input_derivative });
+    }
+    if fmi_model.max_output_derivative_order > 0 {
+        required_functions.push(quote! { <#model_name as fmi2::OutputDerivatives>::get_real_output_derivative });
+    }
+    if fmi_model.can_get_and_set_fmu_state == Some(true) || fmi_model.can_serialize_fmu_state == Some(true) {
+        required_functions.push(quote! { <#model_name as fmi2::FmuStateSnapshot>::snapshot });
+    }
+    if fmi_model.can_serialize_fmu_state == Some(true) {
+        required_functions.push(quote! { fmi2::serialization::serialize_state::<#model_name> });
+        required_functions.push(quote! { fmi2::serialization::deserialize_state::<#model_name> });
+    }
+
+    let fmu_state_ops = match fmi_model.can_get_and_set_fmu_state {
+        Some(false) => quote! { None::<fmi2::FmuStateOps<#model_name>> },
+        _ => quote! { fmi2::fmu_state_ops!(#model_name) },
+    };
+    let fmu_state_serde_ops = match fmi_model.can_serialize_fmu_state {
+        Some(false) => quote! { None::<fmi2::FmuStateSerdeOps<#model_name>> },
+        _ => quote! { fmi2::fmu_state_serde_ops!(#model_name) },
+    };
+
+    let enum_name: String = format!("{}Variables", model_name);
+    let value_reference_enum = syn::Ident::new(&enum_name, model_name.span());
+
+    // Value references that aren't given are assigned in field order, from `vr_base` if it is set
+    // and after the largest given one otherwise. Array fields take one value reference per
+    // element, starting at their id.
+    let mut errors = darling::Error::accumulator();
+    let explicit_end = fields
+        .iter()
+        .filter_map(|f| f.id.map(|VarRefId(id)| id + f.array_len().unwrap_or(1) as u64))
+        .max();
+    if let Some(vr_base) = fmi_model.vr_base {
+        for field in fields.iter().filter(|f| f.id.is_some()) {
+            let vrs = field.value_references();
+            if vrs.end > vr_base {
+                errors.push(
+                    darling::Error::custom(format!(
+                        "value reference {} is not below vr_base = {}, which is where automatic assignment starts",
+                        vrs.end - 1,
+                        vr_base
+                    ))
+                    .with_span(field.ident.as_ref().unwrap()),
+                );
+            }
+        }
+    }
+
+    let mut last_value_ref = fmi_model.vr_base.or(explicit_end).unwrap_or(0);
+    for field in fields.iter_mut() {
+        if field.id.is_none() {
+            field.id = Some(VarRefId(last_value_ref));
+            last_value_ref += field.array_len().unwrap_or(1) as u64;
+        }
+    }
+    let last_value_ref = last_value_ref.max(explicit_end.unwrap_or(0));
+
+    // Get and set dispatch on the value reference alone, so they have to be unique across types
+    for (index, field) in fields.iter().enumerate() {
+        let vrs = field.value_references();
+        let taken = fields[..index].iter().find(|other| {
+            let other = other.value_references();
+            other.start < vrs.end && vrs.start < other.end
+        });
+        if let Some(other) = taken {
+            let collision = vrs.start.max(other.value_references().start);
+            errors.push(
+                darling::Error::custom(format!(
+                    "value reference {} of this {} variable is already taken by the {} variable {}",
+                    collision,
+                    field.fmi_type().name(),
+                    other.fmi_type().name(),
+                    other.ident.as_ref().unwrap()
+                ))
+                .with_span(field.ident.as_ref().unwrap()),
+            );
+        }
+    }
+    for (ident, _, id) in flattened.iter() {
+        if let Some(VarRefId(id)) = id {
+            if let Some(other) = fields.iter().find(|f| f.value_references().contains(id)) {
+                errors.push(
+                    darling::Error::custom(format!(
+                        "the variables of this field start at value reference {}, which is already taken by {}",
+                        id,
+                        other.ident.as_ref().unwrap()
+                    ))
+                    .with_span(ident),
+                );
+            }
+        }
+    }
+    if let Err(errors) = errors.finish() {
+        return errors.write_errors().into();
+    }
+
+    // Flattened children get the value references after the ones of this model, one after the
+    // other. Their spans are only known at runtime, so the offsets are expressions.
+    let mut next_offset = quote! { #last_value_ref };
+    let children: Vec<Child> = flattened
+        .into_iter()
+        .map(|(ident, ty, id)| {
+            let offset = match id {
+                Some(VarRefId(id)) => quote! { #id },
+                None => next_offset.clone(),
+            };
+            next_offset = quote! { (#offset + <#ty as fmi2::FmiModelStructDerive>::value_reference_span()) };
+            Child { ident, ty, offset }
+        })
+        .collect();
+    let child_idents: Vec<&syn::Ident> = children.iter().map(|c| &c.ident).collect();
+    let child_types: Vec<&syn::Type> = children.iter().map(|c| &c.ty).collect();
+    let child_offsets: Vec<&TokenStream> = children.iter().map(|c| &c.offset).collect();
+    let child_names: Vec<String> = children.iter().map(|c| c.ident.to_string()).collect();
+
+    let enum_fields: Vec<TokenStream> = fields
+        .iter()
+        .map(|f| {
+            let name = f.ident.as_ref().unwrap();
+            quote! { #name }
+        })
+        .collect::<Vec<_>>();
+
+    let field_vrs: Vec<TokenStream> = fields
+        .iter()
+        .map(|f: &&mut FmiVariableReceiver| match f.id {
+            Some(VarRefId(id)) => quote! { #id },
+            None => quote! { 0 },
+        })
+        .collect::<Vec<_>>();
+
+    let field_vr_patterns: Vec<TokenStream> = fields
+        .iter()
+        .map(|f| {
+            let vrs = f.value_references();
+            let (first, last) = (vrs.start, vrs.end - 1);
+            if first == last {
+                quote! { #first }
+            } else {
+                quote! { #first..=#last }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let real_fields_access: Vec<TokenStream> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Real)
+        .map(|f| f.access())
+        .collect::<Vec<_>>();
+
+    let real_fields_idents = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Real)
+        .map(|f| {
+            let name = f.ident.as_ref().unwrap();
+            quote! { #name }
+        })
+        .collect::<Vec<_>>();
+
+    let bool_fields_access: Vec<TokenStream> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Boolean)
+        .map(|f| f.access())
+        .collect::<Vec<_>>();
+
+    let bool_fields_idents: Vec<TokenStream> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Boolean)
+        .map(|f| {
+            let name = f.ident.as_ref().unwrap();
+            quote! { #name }
+        })
+        .collect::<Vec<_>>();
+
+    let real_fields_setters: Vec<TokenStream> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Real)
+        .map(|f| {
+            let access = f.access();
+            f.range_checked(&range_policy, quote! { self.#access = value as _ })
+        })
+        .collect::<Vec<_>>();
+
+    let integer_fields_setters: Vec<TokenStream> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Integer)
+        .map(|f| {
+            let (access, ty, name) = (f.access(), f.scalar_type(), f.name_expr());
+            f.range_checked(&range_policy, quote! {
+                self.#access = <#ty as ::core::convert::TryFrom<i32>>::try_from(value).map_err(|_| {
+                    fmi2::FmiError::error(format!("{} is out of range for {}", value, #name))
+                })?
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let integer_fields_access: Vec<TokenStream> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Integer)
+        .map(|f| f.access())
+        .collect::<Vec<_>>();
+
+    let integer_fields_idents: Vec<TokenStream> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Integer)
+        .map(|f| {
+            let name = f.ident.as_ref().unwrap();
+            quote! { #name }
+        })
+        .collect::<Vec<_>>();
+
+    let integer_fields_names: Vec<TokenStream> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Integer)
+        .map(|f| f.name_expr())
+        .collect::<Vec<_>>();
+
+    let enumeration_fields_access: Vec<TokenStream> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Enumeration)
+        .map(|f| f.access())
+        .collect::<Vec<_>>();
+
+    let enumeration_fields_idents: Vec<TokenStream> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Enumeration)
+        .map(|f| {
+            let name = f.ident.as_ref().unwrap();
+            quote! { #name }
+        })
+        .collect::<Vec<_>>();
+
+    let enumeration_fields_types: Vec<&syn::Type> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Enumeration)
+        .map(|f| f.scalar_type())
+        .collect::<Vec<_>>();
+
+    let enumeration_fields_names: Vec<TokenStream> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Enumeration)
+        .map(|f| f.name_expr())
+        .collect::<Vec<_>>();
+
+    let string_fields_access: Vec<TokenStream> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::String)
+        .map(|f| f.access())
+        .collect::<Vec<_>>();
+
+    let string_fields_idents: Vec<TokenStream> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::String)
+        .map(|f| {
+            let name = f.ident.as_ref().unwrap();
+            quote! { #name }
+        })
+        .collect::<Vec<_>>();
+
+    let state_fields_idents: Vec<TokenStream> = state_derivatives
+        .iter()
+        .map(|(state_index, _)| {
+            let name = fields[*state_index].ident.as_ref().unwrap();
+            quote! { #name }
+        })
+        .collect::<Vec<_>>();
+
+    let derivative_fields_idents: Vec<TokenStream> = state_derivatives
+        .iter()
+        .map(|(_, derivative_index)| {
+            let name = fields[*derivative_index].ident.as_ref().unwrap();
+            quote! { #name }
+        })
+        .collect::<Vec<_>>();
+
+    let state_nominals: Vec<f64> = state_derivatives
+        .iter()
+        .map(|(state_index, _)| fields[*state_index].nominal.unwrap_or(1.0))
+        .collect::<Vec<_>>();
+
+    let real_fields_nominals: Vec<f64> = fields
+        .iter()
+        .filter(|x| x.fmi_type() == FmiType::Real)
+        .map(|f| f.nominal.unwrap_or(1.0))
+        .collect::<Vec<_>>();
+
+    let field_causalities: Vec<TokenStream> = fields.iter().map(|f| f.causality_tokens()).collect::<Vec<_>>();
+    let field_variabilities: Vec<TokenStream> = fields.iter().map(|f| f.variability_tokens()).collect::<Vec<_>>();
+    let field_initials: Vec<TokenStream> = fields.iter().map(|f| f.initial_tokens()).collect::<Vec<_>>();
+
+    let state_vector_indices: Vec<usize> = (0..state_derivatives.len()).collect();
+    let number_of_continuous_states = state_derivatives.len();
+
+    // The continuous states of the children follow the ones of this model, in field order
+    let mut next_state = quote! { #number_of_continuous_states };
+    let child_states: Vec<TokenStream> = children
+        .iter()
+        .map(|child| {
+            let ty = &child.ty;
+            let start = next_state.clone();
+            next_state = quote! { (#start + <#ty as fmi2::FmiModelStructDerive>::number_of_continuous_states()) };
+            quote! { #start..#next_state }
+        })
+        .collect();
+
+    let start_values: Vec<TokenStream> = fields
+        .iter()
+        .filter_map(|field| {
+            let Bound(start) = field.starting_value?;
+            let (name, ty) = (field.ident.as_ref().unwrap(), field.scalar_type());
+            Some(match field.array_len() {
+                Some(len) => quote! { self.#name = [#start as #ty; #len]; },
+                None => quote! { self.#name = #start as #ty; },
+            })
+        })
+        .collect();
+
+    // The scalar variables for `model_variables`, array fields have one per element
+    let scalar_variables: Vec<TokenStream> = fields
+        .iter()
+        .flat_map(|field| {
+            let elements: Vec<Option<usize>> = match field.array_len() {
+                Some(len) => (0..len).map(Some).collect(),
+                None => vec![None],
+            };
+            field.scalar_names().into_iter().zip(field.value_references()).zip(elements).map(move |((name, vr), element)| (field, name, vr, element))
+        })
+        .map(|(field, name, value_reference, element)| {
+            let ident = field.ident.as_ref().unwrap();
+            let value = match element {
+                Some(i) => quote! { self.#ident[#i] },
+                None => quote! { self.#ident },
+            };
+
+            let bound = |bound: Option<Bound>, ty: TokenStream| match bound {
+                Some(Bound(value)) => quote! { Some(#value as #ty) },
+                None => quote! { None },
+            };
+            let variable_type = match field.fmi_type() {
+                FmiType::Real => {
+                    let unit = match field.unit.0.as_str() {
+                        "" => quote! { None },
+                        unit => quote! { Some(fmi2::units::lookup(#unit, #unit_registries).into()) },
+                    };
+                    let display_unit = match &field.display_unit {
+                        Some(display_unit) => quote! { Some(#display_unit.to_string()) },
+                        None => quote! { None },
+                    };
+                    let nominal = match field.nominal {
+                        Some(nominal) => quote! { Some(#nominal) },
+                        None => quote! { None },
+                    };
+                    let derivative = match &field.derivative {
+                        Some(state_name) => {
+                            let state = fields.iter().find(|x| x.ident.as_ref().unwrap() == state_name).unwrap();
+                            let state_vr = state.id.unwrap().0;
+                            quote! { Some(offset + #state_vr) }
+                        }
+                        None => quote! { None },
+                    };
+                    let (min, max) = (bound(field.min, quote! { f64 }), bound(field.max, quote! { f64 }));
+                    quote! {
+                        fmi2::model_description::VariableType::Real {
+                            unit: #unit,
+                            display_unit: #display_unit,
+                            min: #min,
+                            max: #max,
+                            nominal: #nominal,
+                            derivative: #derivative,
+                        }
+                    }
+                }
+                FmiType::Integer => {
+                    let (min, max) = (bound(field.min, quote! { i32 }), bound(field.max, quote! { i32 }));
+                    quote! { fmi2::model_description::VariableType::Integer { min: #min, max: #max } }
+                }
+                FmiType::Boolean => quote! { fmi2::model_description::VariableType::Boolean },
+                FmiType::String => quote! { fmi2::model_description::VariableType::String },
+                FmiType::Enumeration => {
+                    let ty = field.scalar_type();
+                    quote! {
+                        fmi2::model_description::VariableType::Enumeration {
+                            declared_type: <#ty as fmi2::FmiEnumeration>::type_definition(),
+                        }
+                    }
+                }
+            };
+
+            let start = match (field.has_start(), field.fmi_type()) {
+                (true, FmiType::Enumeration) => quote! { Some(fmi2::FmiEnumeration::to_value(&#value).to_string()) },
+                (true, _) => quote! { Some(fmi2::model_description::StartValue::start_value(&#value)) },
+                (false, _) => quote! { None },
+            };
+
+            let dependencies = match &field.depends_on {
+                Some(Dependencies(dependencies)) => {
+                    let dependencies = dependencies.iter().map(|(name, kind)| {
+                        let kind = match kind {
+                            DependencyKind::Dependent => quote! { fmi2::model_description::DependencyKind::Dependent },
+                            DependencyKind::Constant => quote! { fmi2::model_description::DependencyKind::Constant },
+                            DependencyKind::Fixed => quote! { fmi2::model_description::DependencyKind::Fixed },
+                            DependencyKind::Tunable => quote! { fmi2::model_description::DependencyKind::Tunable },
+                            DependencyKind::Discrete => quote! { fmi2::model_description::DependencyKind::Discrete },
+                        };
+                        quote! {
+                            fmi2::model_description::Dependency { name: format!("{}{}", prefix, #name), kind: #kind }
+                        }
+                    });
+                    quote! { Some(vec![#(#dependencies),*]) }
+                }
+                None => quote! { None },
+            };
+
+            let description = &field.description.0;
+            let causality = field.causality_tokens();
+            let variability = field.variability_tokens();
+            let initial = field.initial_tokens();
+            quote! {
+                fmi2::model_description::ScalarVariable {
+                    name: format!("{}{}", prefix, #name),
+                    value_reference: offset + #value_reference,
+                    description: #description.to_string(),
+                    causality: #causality,
+                    variability: #variability,
+                    initial: #initial,
+                    variable_type: #variable_type,
+                    start: #start,
+                    dependencies: #dependencies,
+                }
+            }
+        })
+        .collect();
+
+    // Find the variable of a value reference, in this model or else in one of its children
+    let lookup = |dispatch: TokenStream, fallback: TokenStream| {
+        quote! {
+            match #value_reference_enum::from_underlying(value_reference) {
+                Some(vr) => vr,
+                None => {
+                    #dispatch
+                    return #fallback;
+                }
+            }
+        }
+    };
+    let invalid = quote! { Err(fmi2::FmiError::invalid_value_reference(value_reference)) };
+    let call_children = |method: &str, receiver: TokenStream| {
+        let method = syn::Ident::new(method, proc_macro2::Span::call_site());
+        dispatch_to_children(&children, |child, _| {
+            quote! { fmi2::FmiModelStructDerive::#method(#receiver self.#child, value_reference, value) }
+        })
+    };
+    let get_children = |method: &str| {
+        let method = syn::Ident::new(method, proc_macro2::Span::call_site());
+        dispatch_to_children(&children, |child, _| {
+            quote! { fmi2::FmiModelStructDerive::#method(&self.#child, value_reference) }
+        })
+    };
+    let metadata_children = |method: &str| {
+        let method = syn::Ident::new(method, proc_macro2::Span::call_site());
+        dispatch_to_children(&children, |_, ty| {
+            quote! { <#ty as fmi2::FmiModelStructDerive>::#method(value_reference) }
+        })
+    };
+    let get_real = lookup(get_children("get_real_by_value_reference"), invalid.clone());
+    let set_real = lookup(call_children("set_real_by_value_reference", quote! { &mut }), invalid.clone());
+    let get_bool = lookup(get_children("get_bool_by_value_reference"), invalid.clone());
+    let set_bool = lookup(call_children("set_bool_by_value_reference", quote! { &mut }), invalid.clone());
+    let get_integer = lookup(get_children("get_integer_by_value_reference"), invalid.clone());
+    let set_integer = lookup(call_children("set_integer_by_value_reference", quote! { &mut }), invalid.clone());
+    let get_string = lookup(get_children("get_string_by_value_reference"), invalid.clone());
+    let set_string = lookup(call_children("set_string_by_value_reference", quote! { &mut }), invalid);
+    let nominal = lookup(metadata_children("nominal_by_value_reference"), quote! { None });
+    let causality = lookup(metadata_children("causality_by_value_reference"), quote! { None });
+    let variability = lookup(metadata_children("variability_by_value_reference"), quote! { None });
+    let initial = lookup(metadata_children("initial_by_value_reference"), quote! { None });
+
+    // Create the output code
+    let description = fmi_model.description.0;
+    let model_name_str = model_name.to_string();
+    let guid = match fmi_model.guid {
+        Some(Guid(guid)) => quote! { #guid },
+        None => quote! {
+            static GUID: ::std::sync::OnceLock<String> = ::std::sync::OnceLock::new();
+            GUID.get_or_init(|| {
+                let variables = fmi2::FmiModelStructDerive::model_variables(&#model_name::default(), "", 0);
+                fmi2::model_description::interface_guid(#model_name_str, &variables)
+            })
+        },
+    };
+    // The capabilities that are known at compile time. Whether the FMU state can be saved is
+    // detected at runtime, see `fmu_state_ops`.
+    let needs_execution_tool = fmi_model.needs_execution_tool;
+    let can_be_instantiated_only_once_per_process = fmi_model.can_be_instantiated_only_once_per_process;
+    let can_not_use_memory_management_functions = fmi_model.can_not_use_memory_management_functions;
+    let can_handle_variable_communication_step_size = fmi_model.can_handle_variable_communication_step_size;
+    let can_interpolate_inputs = fmi_model.can_interpolate_inputs;
+    let max_output_derivative_order = fmi_model.max_output_derivative_order;
+    let model_exchange_description = if model_exchange {
+        quote! {
+            Some(fmi2::model_description::ModelExchange {
+                model_identifier: #model_name_str.to_string(),
+                needs_execution_tool: #needs_execution_tool,
+                can_be_instantiated_only_once_per_process: #can_be_instantiated_only_once_per_process,
+                can_not_use_memory_management_functions: #can_not_use_memory_management_functions,
+                can_get_and_set_fmu_state,
+                can_serialize_fmu_state,
+                provides_directional_derivative: #provides_directional_derivative,
+                ..Default::default()
+            })
+        }
+    } else {
+        quote! { None }
+    };
+
+    let default_experiment = [fmi_model.start_time, fmi_model.stop_time, fmi_model.tolerance, fmi_model.step_size];
+    let default_experiment = if default_experiment.iter().any(Option::is_some) {
+        let [start_time, stop_time, tolerance, step_size] = default_experiment.map(|value| match value {
+            Some(value) => quote! { Some(#value) },
+            None => quote! { None },
+        });
+        quote! {
+            Some(fmi2::model_description::DefaultExperiment {
+                start_time: #start_time,
+                stop_time: #stop_time,
+                tolerance: #tolerance,
+                step_size: #step_size,
+            })
+        }
+    } else {
+        quote! { None }
+    };
+
+    let capability_check = if required_functions.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            const _: fn() = || {
+                #(let _ = #required_functions;)*
+            };
+        }
+    };
+
+    let dependency_warnings = if dependency_warnings.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            const _: () = {
+                #(#dependency_warnings)*
+            };
+        }
+    };
+
+    let mut output = quote! {
+        #capability_check
+        #dependency_warnings
+
+        // Create the value reference enum
+        #[allow(non_camel_case_types)]
+        #[derive(Copy, Clone)]
+        enum #value_reference_enum {
+            #(#enum_fields),*
+        }
+
+        impl #value_reference_enum {
+            fn to_underlying(&self) -> Option<u64> {
+                match self {
+                    #(#value_reference_enum::#enum_fields => Some(#field_vrs),)*
+                    _ => None,
+                }
+            }
+
+            fn from_underlying(x: u64) -> Option<Self> {
+                match x {
+                    #(#field_vr_patterns => Some(#value_reference_enum::#enum_fields),)*
+                    _ => None,
                 }
             }
         }
 
         impl FmiModelStructDerive for #model_name {
-            fn get_real_by_value_reference(self: &Self, value_reference: u64) -> Option<f64> {
-                let vr = #value_reference_enum::from_underlying(value_reference)?;
+            fn get_real_by_value_reference(&self, value_reference: u64) -> fmi2::FmiResult<f64> {
+                let vr = #get_real;
                 match vr {
-                    #(#value_reference_enum::#real_fields_idents => Some(self.#real_fields_idents),)*
-                    _ => None,
+                    #(#value_reference_enum::#real_fields_idents => Ok(self.#real_fields_access as f64),)*
+                    _ => Err(fmi2::FmiError::invalid_value_reference(value_reference)),
                 }
             }
 
-            fn set_real_by_value_reference(self: &mut Self, value_reference: u64, value: f64) -> Result<(),FMIErrors> {
-                let vr = #value_reference_enum::from_underlying(value_reference).ok_or(FMIErrors::InvalidValueReference)?;
+            fn set_real_by_value_reference(&mut self, value_reference: u64, value: f64) -> fmi2::FmiResult {
+                let vr = #set_real;
                 match vr {
-                    #(#value_reference_enum::#real_fields_idents => {self.#real_fields_idents = value; Ok(())},)*
-                    _ => Err(FMIErrors::InvalidValueReference),
+                    #(#value_reference_enum::#real_fields_idents => #real_fields_setters,)*
+                    _ => Err(fmi2::FmiError::invalid_value_reference(value_reference)),
                 }
             }
 
-            fn get_bool_by_value_reference(self: &Self, value_reference: u64) -> Option<bool> {
-                let vr = #value_reference_enum::from_underlying(value_reference)?;
+            fn get_bool_by_value_reference(&self, value_reference: u64) -> fmi2::FmiResult<bool> {
+                let vr = #get_bool;
                 match vr {
-                    #(#value_reference_enum::#bool_fields_idents => Some(self.#bool_fields_idents),)*
-                    _ => None,
+                    #(#value_reference_enum::#bool_fields_idents => Ok(self.#bool_fields_access),)*
+                    _ => Err(fmi2::FmiError::invalid_value_reference(value_reference)),
+                }
+            }
+
+            fn set_bool_by_value_reference(&mut self, value_reference: u64, value: bool) -> fmi2::FmiResult {
+                let vr = #set_bool;
+                match vr {
+                    #(#value_reference_enum::#bool_fields_idents => {self.#bool_fields_access = value; Ok(())},)*
+                    _ => Err(fmi2::FmiError::invalid_value_reference(value_reference)),
+                }
+            }
+
+
+            fn get_integer_by_value_reference(&self, value_reference: u64) -> fmi2::FmiResult<i32> {
+                let vr = #get_integer;
+                match vr {
+                    #(#value_reference_enum::#integer_fields_idents => {
+                        <i32 as ::core::convert::TryFrom<_>>::try_from(self.#integer_fields_access).map_err(|_| {
+                            fmi2::FmiError::error(format!("{} = {} does not fit into an fmi2Integer", #integer_fields_names, self.#integer_fields_access))
+                        })
+                    },)*
+                    #(#value_reference_enum::#enumeration_fields_idents => {
+                        Ok(fmi2::FmiEnumeration::to_value(&self.#enumeration_fields_access))
+                    },)*
+                    _ => Err(fmi2::FmiError::invalid_value_reference(value_reference)),
                 }
             }
 
-            fn set_bool_by_value_reference(self: &mut Self, value_reference: u64, value: bool) -> Result<(),FMIErrors> {
-                let vr = #value_reference_enum::from_underlying(value_reference).ok_or(FMIErrors::InvalidValueReference)?;
+            fn set_integer_by_value_reference(&mut self, value_reference: u64, value: i32) -> fmi2::FmiResult {
+                let vr = #set_integer;
                 match vr {
-                    #(#value_reference_enum::#bool_fields_idents => {self.#bool_fields_idents = value; Ok(())},)*
-                    _ => Err(FMIErrors::InvalidValueReference),
+                    #(#value_reference_enum::#integer_fields_idents => #integer_fields_setters,)*
+                    #(#value_reference_enum::#enumeration_fields_idents => {
+                        self.#enumeration_fields_access = <#enumeration_fields_types as fmi2::FmiEnumeration>::from_value(value).ok_or_else(|| {
+                            fmi2::FmiError::error(format!("{} is not a valid value for {}", value, #enumeration_fields_names))
+                        })?;
+                        Ok(())
+                    },)*
+                    _ => Err(fmi2::FmiError::invalid_value_reference(value_reference)),
                 }
             }
 
+            fn get_string_by_value_reference(&self, value_reference: u64) -> fmi2::FmiResult<&str> {
+                let vr = #get_string;
+                match vr {
+                    #(#value_reference_enum::#string_fields_idents => Ok(self.#string_fields_access.as_str()),)*
+                    _ => Err(fmi2::FmiError::invalid_value_reference(value_reference)),
+                }
+            }
 
-            fn get_integer_by_value_reference(self: &Self, value_reference: u64) -> Option<i64> {
-                let vr = #value_reference_enum::from_underlying(value_reference)?;
+            fn set_string_by_value_reference(&mut self, value_reference: u64, value: &str) -> fmi2::FmiResult {
+                let vr = #set_string;
                 match vr {
-                    #(#value_reference_enum::#integer_fields_idents => Some(self.#integer_fields_idents),)*
+                    #(#value_reference_enum::#string_fields_idents => {self.#string_fields_access = value.to_owned(); Ok(())},)*
+                    _ => Err(fmi2::FmiError::invalid_value_reference(value_reference)),
+                }
+            }
+
+            fn value_reference_span() -> u64 {
+                let span = #last_value_ref;
+                #(let span = span.max(#child_offsets + <#child_types as fmi2::FmiModelStructDerive>::value_reference_span());)*
+                span
+            }
+
+            #[allow(unused_variables)]
+            fn model_variables(&self, prefix: &str, offset: u64) -> Vec<fmi2::model_description::ScalarVariable> {
+                #[allow(unused_mut)]
+                let mut variables = vec![#(#scalar_variables),*];
+                #(variables.extend(fmi2::FmiModelStructDerive::model_variables(
+                    &self.#child_idents,
+                    &format!("{}{}.", prefix, #child_names),
+                    offset + #child_offsets,
+                ));)*
+                variables
+            }
+
+            fn set_start_values(&mut self) {
+                #(#start_values)*
+                #(fmi2::FmiModelStructDerive::set_start_values(&mut self.#child_idents);)*
+            }
+
+            fn number_of_continuous_states() -> usize {
+                #number_of_continuous_states #(+ <#child_types as fmi2::FmiModelStructDerive>::number_of_continuous_states())*
+            }
+
+            fn number_of_event_indicators() -> usize {
+                #number_of_event_indicators
+            }
+
+            fn get_continuous_states(&self, x: &mut [f64]) {
+                #(x[#state_vector_indices] = self.#state_fields_idents as f64;)*
+                #(fmi2::FmiModelStructDerive::get_continuous_states(&self.#child_idents, &mut x[#child_states]);)*
+            }
+
+            fn set_continuous_states(&mut self, x: &[f64]) {
+                #(self.#state_fields_idents = x[#state_vector_indices] as _;)*
+                #(fmi2::FmiModelStructDerive::set_continuous_states(&mut self.#child_idents, &x[#child_states]);)*
+            }
+
+            fn get_derivatives(&self, dx: &mut [f64]) {
+                #(dx[#state_vector_indices] = self.#derivative_fields_idents as f64;)*
+                #(fmi2::FmiModelStructDerive::get_derivatives(&self.#child_idents, &mut dx[#child_states]);)*
+            }
+
+            fn get_nominals_of_continuous_states(&self, x_nominal: &mut [f64]) {
+                #(x_nominal[#state_vector_indices] = #state_nominals;)*
+                #(fmi2::FmiModelStructDerive::get_nominals_of_continuous_states(&self.#child_idents, &mut x_nominal[#child_states]);)*
+            }
+
+            fn nominal_by_value_reference(value_reference: u64) -> Option<f64> {
+                let vr = #nominal;
+                match vr {
+                    #(#value_reference_enum::#real_fields_idents => Some(#real_fields_nominals),)*
                     _ => None,
                 }
             }
 
-            fn set_integer_by_value_reference(self: &mut Self, value_reference: u64, value: i64) -> Result<(),FMIErrors> {
-                let vr = #value_reference_enum::from_underlying(value_reference).ok_or(FMIErrors::InvalidValueReference)?;
+            fn causality_by_value_reference(value_reference: u64) -> Option<fmi2::Causality> {
+                let vr = #causality;
+                match vr {
+                    #(#value_reference_enum::#enum_fields => Some(#field_causalities),)*
+                }
+            }
+
+            fn variability_by_value_reference(value_reference: u64) -> Option<fmi2::Variability> {
+                let vr = #variability;
                 match vr {
-                    #(#value_reference_enum::#integer_fields_idents => {self.#integer_fields_idents = value; Ok(())},)*
-                    _ => Err(FMIErrors::InvalidValueReference),
+                    #(#value_reference_enum::#enum_fields => Some(#field_variabilities),)*
                 }
             }
 
-            fn to_model_description_xml() -> String {
-                let default = #model_name::default();
-                let json = serde_json::to_value(default);
-                Handlebars::new().render_template(
-                    #xml_model_description,
-                    &json.expect("Could not render template")
-                ).expect("Could not render template")
+            fn initial_by_value_reference(value_reference: u64) -> Option<fmi2::Initial> {
+                let vr = #initial;
+                match vr {
+                    #(#value_reference_enum::#enum_fields => #field_initials,)*
+                }
+            }
+
+            fn model_description() -> Result<fmi2::model_description::ModelDescription, fmi2::model_description::ModelError> {
+                let mut model = #model_name::default();
+                fmi2::FmiModelStructDerive::set_start_values(&mut model);
+                let variables = fmi2::FmiModelStructDerive::model_variables(&model, "", 0);
+                let can_get_and_set_fmu_state = #fmu_state_ops.is_some();
+                let can_serialize_fmu_state = can_get_and_set_fmu_state && #fmu_state_serde_ops.is_some();
+                Ok(fmi2::model_description::ModelDescription {
+                    description: #description.to_string(),
+                    variable_naming_convention: fmi2::model_description::VariableNamingConvention::Structured,
+                    number_of_event_indicators: #number_of_event_indicators,
+                    model_exchange: #model_exchange_description,
+                    co_simulation: Some(fmi2::model_description::CoSimulation {
+                        model_identifier: #model_name_str.to_string(),
+                        needs_execution_tool: #needs_execution_tool,
+                        can_handle_variable_communication_step_size: #can_handle_variable_communication_step_size,
+                        can_interpolate_inputs: #can_interpolate_inputs,
+                        max_output_derivative_order: #max_output_derivative_order,
+                        can_run_asynchronuously: false,
+                        can_be_instantiated_only_once_per_process: #can_be_instantiated_only_once_per_process,
+                        can_not_use_memory_management_functions: #can_not_use_memory_management_functions,
+                        can_get_and_set_fmu_state,
+                        can_serialize_fmu_state,
+                        provides_directional_derivative: #provides_directional_derivative,
+                        source_files: Vec::new(),
+                    }),
+                    log_categories: fmi2::logger::Category::ALL
+                        .iter()
+                        .map(|category| fmi2::model_description::LogCategory {
+                            name: category.name().to_string(),
+                            description: String::new(),
+                        })
+                        .collect(),
+                    default_experiment: #default_experiment,
+                    ..fmi2::model_description::ModelDescription::new(#model_name_str, #model_name::guid(), variables)?
+                })
             }
 
             fn guid() -> &'static str {
@@ -502,736 +1629,1357 @@ pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
         
     };
 
+
+    // The Model Exchange interface is only implemented for models with continuous states or
+    // event indicators, everything else gets the stubs.
+    let model_exchange_exports = if model_exchange {
+        quote! {
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2EnterEventMode(c: fmi2Component) -> fmi2Status {
+                let body = |instance: &mut fmi2::Instance<#model_name>| {
+                    let result = fmi2::EventModel::enter_event_mode(&mut instance.model);
+                    instance.transition("fmi2EnterEventMode", result, fmi2::ModelState::EventMode)
+                };
+                unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::EnterEventMode, body) }
+            }
+
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2NewDiscreteStates(
+                c: fmi2Component,
+                fmi2eventInfo: *mut fmi2EventInfo,
+            ) -> fmi2Status {
+                let body = |instance: &mut fmi2::Instance<#model_name>| {
+                    if fmi2eventInfo.is_null() {
+                        return instance.fail("fmi2NewDiscreteStates", "Null pointer passed for fmi2eventInfo");
+                    }
+
+                    let mut info = fmi2::EventInfo::default();
+                    let result = fmi2::EventModel::update_discrete_states(&mut instance.model, &mut info);
+                    let status = instance.status("fmi2NewDiscreteStates", result);
+                    if status != fmi2Status_fmi2OK && status != fmi2Status_fmi2Warning {
+                        return status;
+                    }
+
+                    let event_info: &mut fmi2EventInfo = unsafe { &mut *fmi2eventInfo };
+                    event_info.newDiscreteStatesNeeded = info.new_discrete_states_needed as fmi2Boolean;
+                    event_info.terminateSimulation = info.terminate_simulation as fmi2Boolean;
+                    event_info.nominalsOfContinuousStatesChanged = info.nominals_of_continuous_states_changed as fmi2Boolean;
+                    event_info.valuesOfContinuousStatesChanged = info.values_of_continuous_states_changed as fmi2Boolean;
+                    event_info.nextEventTimeDefined = info.next_event_time.is_some() as fmi2Boolean;
+                    event_info.nextEventTime = info.next_event_time.unwrap_or(0.0);
+                    status
+                };
+                unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::NewDiscreteStates, body) }
+            }
+
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2EnterContinuousTimeMode(c: fmi2Component) -> fmi2Status {
+                let body = |instance: &mut fmi2::Instance<#model_name>| {
+                    instance.state = fmi2::ModelState::ContinuousTimeMode;
+                    fmi2Status_fmi2OK
+                };
+                unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::EnterContinuousTimeMode, body) }
+            }
+
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2CompletedIntegratorStep(
+                c: fmi2Component,
+                _noSetFMUStatePriorToCurrentPoint: fmi2Boolean,
+                enterEventMode: *mut fmi2Boolean,
+                terminateSimulation: *mut fmi2Boolean,
+            ) -> fmi2Status {
+                let body = |instance: &mut fmi2::Instance<#model_name>| {
+                    if enterEventMode.is_null() {
+                        return instance.fail("fmi2CompletedIntegratorStep", "Null pointer passed for enterEventMode");
+                    }
+
+                    if terminateSimulation.is_null() {
+                        return instance.fail("fmi2CompletedIntegratorStep", "Null pointer passed for terminateSimulation");
+                    }
+
+                    let mut info = fmi2::IntegratorStepInfo::default();
+                    let result = fmi2::ContinuousModel::completed_integrator_step(&mut instance.model, &mut info);
+                    let status = instance.status("fmi2CompletedIntegratorStep", result);
+                    if status != fmi2Status_fmi2OK && status != fmi2Status_fmi2Warning {
+                        return status;
+                    }
+
+                    unsafe {
+                        *enterEventMode = info.enter_event_mode as fmi2Boolean;
+                        *terminateSimulation = info.terminate_simulation as fmi2Boolean;
+                    }
+                    status
+                };
+                unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::CompletedIntegratorStep, body) }
+            }
+
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2SetTime(c: fmi2Component, time: fmi2Real) -> fmi2Status {
+                let body = |instance: &mut fmi2::Instance<#model_name>| {
+                    let x = &mut instance.model;
+                    fmi2::ContinuousModel::set_time(x, time);
+                    fmi2Status_fmi2OK
+                };
+                unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::SetTime, body) }
+            }
+
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2SetContinuousStates(
+                c: fmi2Component,
+                x: *const fmi2Real,
+                nx: usize,
+            ) -> fmi2Status {
+                let body = |instance: &mut fmi2::Instance<#model_name>| {
+                    if nx != #model_name::number_of_continuous_states() {
+                        return instance.fail("fmi2SetContinuousStates", &format!(
+                            "Expected {} states, got {}",
+                            #model_name::number_of_continuous_states(),
+                            nx
+                        ));
+                    }
+
+                    if nx == 0 {
+                        return fmi2Status_fmi2OK;
+                    }
+
+                    if x.is_null() {
+                        return instance.fail("fmi2SetContinuousStates", "Null pointer passed for x");
+                    }
+
+                    let model = &mut instance.model;
+                    let states: &[f64] = unsafe { std::slice::from_raw_parts(x, nx) };
+                    model.set_continuous_states(states);
+                    fmi2Status_fmi2OK
+                };
+                unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::SetContinuousStates, body) }
+            }
+
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2GetDerivatives(
+                c: fmi2Component,
+                derivatives: *mut fmi2Real,
+                nx: usize,
+            ) -> fmi2Status {
+                let body = |instance: &mut fmi2::Instance<#model_name>| {
+                    if nx != #model_name::number_of_continuous_states() {
+                        return instance.fail("fmi2GetDerivatives", &format!(
+                            "Expected {} states, got {}",
+                            #model_name::number_of_continuous_states(),
+                            nx
+                        ));
+                    }
+
+                    if nx == 0 {
+                        return fmi2Status_fmi2OK;
+                    }
+
+                    if derivatives.is_null() {
+                        return instance.fail("fmi2GetDerivatives", "Null pointer passed for derivatives");
+                    }
+
+                    let result = fmi2::ContinuousModel::compute_derivatives(&mut instance.model);
+                    let status = instance.status("fmi2GetDerivatives", result);
+                    if status != fmi2Status_fmi2OK && status != fmi2Status_fmi2Warning {
+                        return status;
+                    }
+                    let dx: &mut [f64] = unsafe { std::slice::from_raw_parts_mut(derivatives, nx) };
+                    instance.model.get_derivatives(dx);
+                    status
+                };
+                unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::GetDerivatives, body) }
+            }
+
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2GetEventIndicators(
+                c: fmi2Component,
+                eventIndicators: *mut fmi2Real,
+                ni: usize,
+            ) -> fmi2Status {
+                let body = |instance: &mut fmi2::Instance<#model_name>| {
+                    if ni != #model_name::number_of_event_indicators() {
+                        return instance.fail("fmi2GetEventIndicators", &format!(
+                            "Expected {} event indicators, got {}",
+                            #model_name::number_of_event_indicators(),
+                            ni
+                        ));
+                    }
+
+                    if ni == 0 {
+                        return fmi2Status_fmi2OK;
+                    }
+
+                    if eventIndicators.is_null() {
+                        return instance.fail("fmi2GetEventIndicators", "Null pointer passed for eventIndicators");
+                    }
+
+                    let indicators: &mut [f64] = unsafe { std::slice::from_raw_parts_mut(eventIndicators, ni) };
+                    let result = fmi2::EventModel::event_indicators(&mut instance.model, indicators);
+                    instance.status("fmi2GetEventIndicators", result)
+                };
+                unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::GetEventIndicators, body) }
+            }
+
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2GetContinuousStates(
+                c: fmi2Component,
+                x: *mut fmi2Real,
+                nx: usize,
+            ) -> fmi2Status {
+                let body = |instance: &mut fmi2::Instance<#model_name>| {
+                    if nx != #model_name::number_of_continuous_states() {
+                        return instance.fail("fmi2GetContinuousStates", &format!(
+                            "Expected {} states, got {}",
+                            #model_name::number_of_continuous_states(),
+                            nx
+                        ));
+                    }
+
+                    if nx == 0 {
+                        return fmi2Status_fmi2OK;
+                    }
+
+                    if x.is_null() {
+                        return instance.fail("fmi2GetContinuousStates", "Null pointer passed for x");
+                    }
+
+                    let model = &mut instance.model;
+                    let states: &mut [f64] = unsafe { std::slice::from_raw_parts_mut(x, nx) };
+                    model.get_continuous_states(states);
+                    fmi2Status_fmi2OK
+                };
+                unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::GetContinuousStates, body) }
+            }
+
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2GetNominalsOfContinuousStates(
+                c: fmi2Component,
+                x_nominal: *mut fmi2Real,
+                nx: usize,
+            ) -> fmi2Status {
+                let body = |instance: &mut fmi2::Instance<#model_name>| {
+                    if nx != #model_name::number_of_continuous_states() {
+                        return instance.fail("fmi2GetNominalsOfContinuousStates", &format!(
+                            "Expected {} states, got {}",
+                            #model_name::number_of_continuous_states(),
+                            nx
+                        ));
+                    }
+
+                    if nx == 0 {
+                        return fmi2Status_fmi2OK;
+                    }
+
+                    if x_nominal.is_null() {
+                        return instance.fail("fmi2GetNominalsOfContinuousStates", "Null pointer passed for x_nominal");
+                    }
+
+                    let model = &mut instance.model;
+                    let nominals: &mut [f64] = unsafe { std::slice::from_raw_parts_mut(x_nominal, nx) };
+                    model.get_nominals_of_continuous_states(nominals);
+                    fmi2Status_fmi2OK
+                };
+                unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::GetNominalsOfContinuousStates, body) }
+            }
+        }
+    } else {
+        quote! {
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2EnterEventMode(c: fmi2Component) -> fmi2Status {
+                unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2EnterEventMode", "Not supported by this FMU") }
+            }
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2NewDiscreteStates(
+                c: fmi2Component,
+                _fmi2eventInfo: *mut fmi2EventInfo,
+            ) -> fmi2Status {
+                unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2NewDiscreteStates", "Not supported by this FMU") }
+            }
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2EnterContinuousTimeMode(c: fmi2Component) -> fmi2Status {
+                unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2EnterContinuousTimeMode", "Not supported by this FMU") }
+            }
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2CompletedIntegratorStep(
+                c: fmi2Component,
+                _noSetFMUStatePriorToCurrentPoint: fmi2Boolean,
+                _enterEventMode: *mut fmi2Boolean,
+                _terminateSimulation: *mut fmi2Boolean,
+            ) -> fmi2Status {
+                unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2CompletedIntegratorStep", "Not supported by this FMU") }
+            }
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2SetTime(c: fmi2Component, _time: fmi2Real) -> fmi2Status {
+                unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2SetTime", "Not supported by this FMU") }
+            }
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2SetContinuousStates(
+                c: fmi2Component,
+                _x: *const fmi2Real,
+                _nx: usize,
+            ) -> fmi2Status {
+                unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2SetContinuousStates", "Not supported by this FMU") }
+            }
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2GetDerivatives(
+                c: fmi2Component,
+                _derivatives: *mut fmi2Real,
+                _nx: usize,
+            ) -> fmi2Status {
+                unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2GetDerivatives", "Not supported by this FMU") }
+            }
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2GetEventIndicators(
+                c: fmi2Component,
+                _eventIndicators: *mut fmi2Real,
+                _ni: usize,
+            ) -> fmi2Status {
+                unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2GetEventIndicators", "Not supported by this FMU") }
+            }
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2GetContinuousStates(
+                c: fmi2Component,
+                _x: *mut fmi2Real,
+                _nx: usize,
+            ) -> fmi2Status {
+                unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2GetContinuousStates", "Not supported by this FMU") }
+            }
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2GetNominalsOfContinuousStates(
+                c: fmi2Component,
+                _x_nominal: *mut fmi2Real,
+                _nx: usize,
+            ) -> fmi2Status {
+                unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2GetNominalsOfContinuousStates", "Not supported by this FMU") }
+            }
+        }
+    };
+
+    let directional_derivative = match fmi_model.directional_derivatives {
+        DirectionalDerivatives::None => None,
+        DirectionalDerivatives::Analytic => Some(quote! {
+            fmi2::DirectionalDerivative::directional_derivative(x, &unknowns, &knowns, dv_known, dv_unknown)
+        }),
+        DirectionalDerivatives::FiniteDifference => {
+            let step = fmi_model.finite_difference_step.unwrap_or(1e-6);
+            Some(quote! {
+                fmi2::finite_difference_directional_derivative(x, &unknowns, &knowns, dv_known, dv_unknown, #step, |x| {
+                    fmi2::ContinuousModel::compute_derivatives(x)
+                })
+            })
+        }
+    };
+
+    let input_derivatives_export = if fmi_model.can_interpolate_inputs {
+        quote! {
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2SetRealInputDerivatives(
+                c: fmi2Component,
+                vr: *const fmi2ValueReference,
+                nvr: usize,
+                order: *const fmi2Integer,
+                value: *const fmi2Real,
+            ) -> fmi2Status {
+                let body = |instance: &mut fmi2::Instance<#model_name>| {
+                    if nvr > 0 && (vr.is_null() || order.is_null() || value.is_null()) {
+                        return instance.fail("fmi2SetRealInputDerivatives", "Null pointer passed");
+                    }
+
+                    let mut status = fmi2Status_fmi2OK;
+                    for i in 0..nvr {
+                        let (vr, order, value) = unsafe { (*vr.add(i) as u64, *order.add(i), *value.add(i)) };
+                        let result = if #model_name::causality_by_value_reference(vr) != Some(fmi2::Causality::Input) {
+                            Err(fmi2::FmiError::error(format!("Variable {} is not an input", vr)))
+                        } else if order < 1 {
+                            Err(fmi2::FmiError::error(format!("Invalid derivative order {}", order)))
+                        } else {
+                            fmi2::InputDerivatives::set_real_input_derivative(&mut instance.model, vr, order, value)
+                        };
+
+                        if let Err(e) = result {
+                            status = instance.report("fmi2SetRealInputDerivatives", &e);
+                            if e.severity > fmi2::Severity::Warning {
+                                return status;
+                            }
+                        }
+                    }
+                    status
+                };
+                unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::SetRealInputDerivatives, body) }
+            }
+        }
+    } else {
+        quote! {
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2SetRealInputDerivatives(
+                c: fmi2Component,
+                _vr: *const fmi2ValueReference,
+                _nvr: usize,
+                _order: *const fmi2Integer,
+                _value: *const fmi2Real,
+            ) -> fmi2Status {
+                unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2SetRealInputDerivatives", "Not supported by this FMU") }
+            }
+        }
+    };
+
+    let max_output_derivative_order = fmi_model.max_output_derivative_order as i32;
+    let output_derivatives_export = if max_output_derivative_order > 0 {
+        quote! {
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2GetRealOutputDerivatives(
+                c: fmi2Component,
+                vr: *const fmi2ValueReference,
+                nvr: usize,
+                order: *const fmi2Integer,
+                value: *mut fmi2Real,
+            ) -> fmi2Status {
+                let body = |instance: &mut fmi2::Instance<#model_name>| {
+                    if nvr > 0 && (vr.is_null() || order.is_null() || value.is_null()) {
+                        return instance.fail("fmi2GetRealOutputDerivatives", "Null pointer passed");
+                    }
+
+                    for i in 0..nvr {
+                        let (vr, order) = unsafe { (*vr.add(i) as u64, *order.add(i)) };
+                        let result = if #model_name::causality_by_value_reference(vr) != Some(fmi2::Causality::Output) {
+                            Err(fmi2::FmiError::error(format!("Variable {} is not an output", vr)))
+                        } else if order < 1 || order > #max_output_derivative_order {
+                            Err(fmi2::FmiError::error(format!("Invalid derivative order {}", order)))
+                        } else {
+                            fmi2::OutputDerivatives::get_real_output_derivative(&instance.model, vr, order)
+                        };
+
+                        match result {
+                            Ok(v) => unsafe { *value.add(i) = v },
+                            Err(e) => return instance.report("fmi2GetRealOutputDerivatives", &e),
+                        }
+                    }
+                    fmi2Status_fmi2OK
+                };
+                unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::GetRealOutputDerivatives, body) }
+            }
+        }
+    } else {
+        quote! {
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2GetRealOutputDerivatives(
+                c: fmi2Component,
+                _vr: *const fmi2ValueReference,
+                _nvr: usize,
+                _order: *const fmi2Integer,
+                _value: *mut fmi2Real,
+            ) -> fmi2Status {
+                unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2GetRealOutputDerivatives", "Not supported by this FMU") }
+            }
+        }
+    };
+
+    let directional_derivative_export = match directional_derivative {
+        Some(directional_derivative) => quote! {
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2GetDirectionalDerivative(
+                c: fmi2Component,
+                vUnknown_ref: *const fmi2ValueReference,
+                nUnknown: usize,
+                vKnown_ref: *const fmi2ValueReference,
+                nKnown: usize,
+                dvKnown: *const fmi2Real,
+                dvUnknown: *mut fmi2Real,
+            ) -> fmi2Status {
+                let body = |instance: &mut fmi2::Instance<#model_name>| {
+                    if nUnknown == 0 {
+                        return fmi2Status_fmi2OK;
+                    }
+
+                    if vUnknown_ref.is_null() || dvUnknown.is_null() || (nKnown > 0 && (vKnown_ref.is_null() || dvKnown.is_null())) {
+                        return instance.fail("fmi2GetDirectionalDerivative", "Null pointer passed");
+                    }
+
+                    let unknowns: Vec<u64> = unsafe { std::slice::from_raw_parts(vUnknown_ref, nUnknown) }
+                        .iter()
+                        .map(|vr| *vr as u64)
+                        .collect();
+                    let knowns: Vec<u64> = if nKnown > 0 {
+                        unsafe { std::slice::from_raw_parts(vKnown_ref, nKnown) }
+                            .iter()
+                            .map(|vr| *vr as u64)
+                            .collect()
+                    } else {
+                        Vec::new()
+                    };
+                    let dv_known: &[f64] = if nKnown > 0 {
+                        unsafe { std::slice::from_raw_parts(dvKnown, nKnown) }
+                    } else {
+                        &[]
+                    };
+                    let dv_unknown: &mut [f64] = unsafe { std::slice::from_raw_parts_mut(dvUnknown, nUnknown) };
+
+                    let x = &mut instance.model;
+                    let result = #directional_derivative;
+                    instance.status("fmi2GetDirectionalDerivative", result)
+                };
+                unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::GetDirectionalDerivative, body) }
+            }
+        },
+        None => quote! {
+            #[no_mangle]
+            #[allow(clippy::not_unsafe_ptr_arg_deref)]
+            pub extern "C" fn fmi2GetDirectionalDerivative(
+                c: fmi2Component,
+                _vUnknown_ref: *const fmi2ValueReference,
+                _nUnknown: usize,
+                _vKnown_ref: *const fmi2ValueReference,
+                _nKnown: usize,
+                _dvKnown: *const fmi2Real,
+                _dvUnknown: *mut fmi2Real,
+            ) -> fmi2Status {
+                unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2GetDirectionalDerivative", "Not supported by this FMU") }
+            }
+        },
+    };
+
     if fmi_model.export.0 {
         output.extend(quote!{
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2GetTypesPlatform() -> *const c_char {
             "default".as_ptr() as *const c_char
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2GetVersion() -> *const c_char {
             "2.0".as_ptr() as *const c_char
         }
 
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2SetDebugLogging(
-            c: *mut c_void,
+            c: fmi2Component,
             loggingOn: fmi2Boolean,
-            _nCategories: usize,
-            _categories: *const fmi2String,
+            nCategories: usize,
+            categories: *const fmi2String,
         ) -> fmi2Status {
-            assert!(
-                std::ptr::null() != c as *mut c_void,
-                "fmi2FreeInstance: Null pointer passed"
-            );
-
-            let x: &mut #model_name = unsafe { &mut *(c as *mut #model_name) };
-
-            // https://stackoverflow.com/questions/26117197/create-interface-to-c-function-pointers-in-rust
-            // Create a c-string from a rust string and report it
-
-            // match x.functions.logger {
-            //     Some(f) => {
-            //         // TODO(cw): Create a wrapper around calling this function (and other callback functions)
-            //         let category = CString::new("log").unwrap();
-            //         let message = CString::new("fmi2SetDebugLogging: loggingOn = %d").unwrap();
-            //         unsafe {
-            //             f(
-            //                 c,
-            //                 x.instanceName,
-            //                 fmi2Status_fmi2OK,
-            //                 category.into_raw(),
-            //                 message.into_raw(),
-            //                 loggingOn,
-            //             );
-            //         }
-            //     }
-            //     None => {
-            //         ();
-            //     }
-            // }
-
-            match x.state {
-                ModelState::Error => fmi2Status_fmi2Error,
-                _ => {
-                    x.loggingOn = loggingOn;
-                    fmi2Status_fmi2OK
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                let categories = unsafe { fmi2::logger::category_names(categories, nCategories) };
+                match instance.logger.set_debug_logging(loggingOn == fmi2True as fmi2Boolean, &categories) {
+                    Ok(_) => {
+                        instance.logger.log(
+                            fmi2::logger::Category::FmiCalls,
+                            &format!("fmi2SetDebugLogging: loggingOn = {}, categories = {:?}", loggingOn, categories),
+                        );
+                        fmi2Status_fmi2OK
+                    }
+                    Err(e) => instance.fail("fmi2SetDebugLogging", &e.to_string()),
                 }
-            }
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::SetDebugLogging, body) }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2Instantiate(
             instanceName: fmi2String,
             fmuType: fmi2Type,
             fmuGUID: fmi2String,
             _fmuResourceLocation: fmi2String,
-            functions: fmi2CallbackFunctions,
+            functions: *const fmi2CallbackFunctions,
             _visible: fmi2Boolean,
             loggingOn: fmi2Boolean,
-        ) -> *mut #model_name {
-            assert!(
-                std::ptr::null() != instanceName as *mut c_void,
-                "fmi2Instantiate: Null pointer passed"
-            );
-
-            assert!(
-                std::ptr::null() != fmuGUID as *mut c_void,
-                "fmi2Instantiate: Null pointer passed"
-            );
-
-            let guid = unsafe { CStr::from_ptr(fmuGUID as *mut c_char) };
+        ) -> fmi2Component {
+            let callbacks = if functions.is_null() { None } else { Some(unsafe { *functions }) };
+            let logging_on = loggingOn == fmi2True as fmi2Boolean;
+            let mut logger = fmi2::logger::Logger::new("", callbacks, logging_on);
+            if instanceName.is_null() || fmuGUID.is_null() {
+                logger.log(fmi2::logger::Category::Error, "fmi2Instantiate: Null pointer passed for instanceName or fmuGUID");
+                return std::ptr::null_mut();
+            }
 
-            println!("fmi2Instantiate: GUID = {}", guid.to_str().unwrap());
-            assert!(
-                guid.to_str().unwrap() == #model_name::guid(),
-                "fmi2Instantiate: Invalid GUID"
-            );
+            // The GUID and the model come from model code, which may panic
+            let instantiate = std::panic::AssertUnwindSafe(|| {
+                let instance_name = unsafe { CStr::from_ptr(instanceName as *mut c_char) }.to_string_lossy();
+                let guid = unsafe { CStr::from_ptr(fmuGUID as *mut c_char) }.to_string_lossy();
+                logger = fmi2::logger::Logger::new(&instance_name, callbacks, logging_on);
+
+                if fmuType == fmi2Type_fmi2ModelExchange && !#model_exchange {
+                    logger.log(
+                        fmi2::logger::Category::Error,
+                        &format!("fmi2Instantiate: {} does not support Model Exchange", #model_name::model_name()),
+                    );
+                    return None;
+                }
 
-            let mut model: Box<#model_name> = Box::new(
-                #model_name::instantiate()
-                    .expect("fmi2Instantiate: Failed to instantiate model")
-            );
+                logger.log(
+                    fmi2::logger::Category::FmiCalls,
+                    &format!("fmi2Instantiate: instanceName = {}, GUID = {}", instance_name, guid),
+                );
+                if guid != #model_name::guid() {
+                    logger.log(
+                        fmi2::logger::Category::Error,
+                        &format!("fmi2Instantiate: Invalid GUID, expected {}", #model_name::guid()),
+                    );
+                    return None;
+                }
 
-            if model.loggingOn == fmi2True as fmi2Boolean {
-                // // https://stackoverflow.com/questions/26117197/create-interface-to-c-function-pointers-in-rust
-                // // Create a c-string from a rust string and report it
-                let category = CString::new("log").unwrap();
-                let message = CString::new("fmi2Instantiate: instanceName = %s").unwrap();
-                // unsafe {
-                //     // model.functions.logger.unwrap()(
-                //     //     model.as_mut() as *mut #model_name as *mut c_void,
-                //     //     instanceName,
-                //     //     fmi2Status_fmi2OK,
-                //     //     category.into_raw(),
-                //     //     message.into_raw(),
-                //     //     instanceName,
-                //     // );
-                // }
+                // Model code may log while it is instantiated
+                let _logger = fmi2::logger::CurrentLogger::enter(Some(&logger));
+                match fmi2::instantiate_with_start_values::<#model_name>() {
+                    Ok(model) => Some(Box::new(fmi2::Instance::new(
+                        &instance_name,
+                        fmuType,
+                        &guid,
+                        callbacks,
+                        logging_on,
+                        model,
+                    ))),
+                    Err(e) => {
+                        logger.log(e.severity.category(), &format!("fmi2Instantiate: {}", e));
+                        None
+                    }
+                }
+            });
+
+            match std::panic::catch_unwind(instantiate) {
+                Ok(Some(instance)) => Box::into_raw(instance) as fmi2Component,
+                Ok(None) => std::ptr::null_mut(),
+                Err(payload) => {
+                    logger.log(
+                        fmi2::logger::Category::Error,
+                        &format!("fmi2Instantiate: Panicked: {}", fmi2::instance::panic_message(&payload)),
+                    );
+                    std::ptr::null_mut()
+                }
             }
-
-            Box::into_raw(model) as *mut #model_name
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2FreeInstance(c: fmi2Component) -> () {
-            // let model = #model_name::from_c_ptr(c as *mut c_void)
-            //     .expect("fmi2FreeInstance: Failed to get model from c_ptr");
-
-            // https://stackoverflow.com/questions/26117197/create-interface-to-c-function-pointers-in-rust
-            // Create a c-string from a rust string and report it
-            // if model.loggingOn == fmi2True as fmi2Boolean {
-            //     let category = CString::new("log").unwrap();
-            //     let message = CString::new("fmi2FreeInstance: instanceName = %s").unwrap();
-            //     unsafe {
-            //         // x.functions.logger.unwrap()(
-            //         //     c,
-            //         //     x.instanceName,
-            //         //     fmi2Status_fmi2OK,
-            //         //     category.into_raw(),
-            //         //     message.into_raw(),
-            //         //     x.instanceName,
-            //         // );
-            //     }
-            // }
-
-        //     match x.functions.freeMemory {
-        //         None => {
-        //             ();
-        //         }
-        //         Some(f) => unsafe {
-        //             f(c);
-        //         },
-        //     }
-        // }
-            // TODO: Figure out a way to free this.  It's a Box, so it should be freed automatically, but it's not.
-            let model = unsafe { Box::from_raw(c as *mut #model_name) };
+            if c.is_null() {
+                return;
+            }
+
+            unsafe { fmi2::Instance::<#model_name>::log(c, fmi2::logger::Category::FmiCalls, "fmi2FreeInstance") };
+            // A panic while dropping the model must not unwind into the importer either
+            let _ = std::panic::catch_unwind(|| {
+                drop(unsafe { Box::from_raw(c as *mut fmi2::Instance<#model_name>) });
+            });
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2SetupExperiment(
-            c: fmi2Component,
-            _toleranceDefined: fmi2Boolean,
-            _tolerance: fmi2Real,
-            _startTime: fmi2Real,
-            _stopTimeDefined: fmi2Boolean,
-            _stopTime: fmi2Real,
-        ) -> fmi2Status {
-            assert!(
-                std::ptr::null() != c as *mut c_void,
-                "fmi2FreeInstance: Null pointer passed"
-            );
-
-            // let x: &mut #model_name = unsafe { &mut *(c as *mut #model_name) };
-            // println!("{:?}", x);
-
-            // x.startTime = startTime;
-            // x.stopTime = if stopTimeDefined == fmi2True as fmi2Boolean {
-            //     stopTime
-            // } else {
-            //     0.0
-            // };
-
-            // x.stopTimeDefined = stopTimeDefined;
-
-            fmi2Status_fmi2OK
+            c: fmi2Component,
+            toleranceDefined: fmi2Boolean,
+            tolerance: fmi2Real,
+            startTime: fmi2Real,
+            stopTimeDefined: fmi2Boolean,
+            stopTime: fmi2Real,
+        ) -> fmi2Status {
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                instance.experiment = fmi2::ExperimentSetup {
+                    tolerance: if toleranceDefined == fmi2True as fmi2Boolean { Some(tolerance) } else { None },
+                    start_time: startTime,
+                    stop_time: if stopTimeDefined == fmi2True as fmi2Boolean { Some(stopTime) } else { None },
+                };
+                fmi2Status_fmi2OK
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::SetupExperiment, body) }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2EnterInitializationMode(c: fmi2Component) -> fmi2Status {
-            let model = #model_name::from_c_ptr(c as *mut c_void);
-
-            match model {
-                Ok(x) => {
-                    x.state = ModelState::Initialized;
-                    fmi2Status_fmi2OK
-                }
-                Err(_) => fmi2Status_fmi2Error,
-            }
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                instance.state = fmi2::ModelState::InitializationMode;
+                fmi2Status_fmi2OK
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::EnterInitializationMode, body) }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2ExitInitializationMode(c: fmi2Component) -> fmi2Status {
-            match #model_name::from_c_ptr(c as *mut c_void) {
-                Ok(x) => {
-                    x.state = ModelState::Instantiated;
-                    fmi2Status_fmi2OK
-                }
-                Err(_) => fmi2Status_fmi2Error,
-            }
-
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                instance.state = if instance.fmu_type == fmi2Type_fmi2ModelExchange {
+                    fmi2::ModelState::EventMode
+                } else {
+                    fmi2::ModelState::StepComplete
+                };
+                fmi2Status_fmi2OK
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::ExitInitializationMode, body) }
         }
 
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2Terminate(c: fmi2Component) -> fmi2Status {
-            match #model_name::from_c_ptr(c as *mut c_void) {
-                Ok(x) => {
-                    x.state = ModelState::Terminated;
-                    fmi2Status_fmi2OK
-                }
-                Err(_) => fmi2Status_fmi2Error,
-            }
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                instance.state = fmi2::ModelState::Terminated;
+                fmi2Status_fmi2OK
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::Terminate, body) }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2Reset(c: fmi2Component) -> fmi2Status {
-            match #model_name::from_c_ptr(c as *mut c_void) {
-                Ok(x) => {
-                    *x = #model_name::default();
-                    fmi2Status_fmi2OK
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                match fmi2::instantiate_with_start_values::<#model_name>() {
+                    Ok(model) => {
+                        instance.model = model;
+                        instance.state = fmi2::ModelState::Instantiated;
+                        instance.experiment = fmi2::ExperimentSetup::default();
+                        fmi2Status_fmi2OK
+                    }
+                    Err(e) => instance.report("fmi2Reset", &e),
                 }
-                Err(_) => fmi2Status_fmi2Error,
-            }
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::Reset, body) }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2GetReal(
             c: fmi2Component,
             vr: *const fmi2ValueReference,
             nvr: usize,
             value: *mut fmi2Real,
         ) -> fmi2Status {
-            assert!(
-                std::ptr::null() != c as *mut c_void,
-                "fmi2GetReal: Null pointer passed"
-            );
-
-            assert!(
-                value != std::ptr::null_mut(),
-                "fmi2GetReal: Null pointer passed"
-            );
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                if nvr == 0 {
+                    return fmi2Status_fmi2OK;
+                }
 
-            assert!(
-                vr != std::ptr::null_mut(),
-                "fmi2GetReal: Null pointer passed"
-            );
+                if value.is_null() {
+                    return instance.fail("fmi2GetReal", "Null pointer passed for value");
+                }
 
-            match #model_name::from_c_ptr(c as *mut c_void) {
-                Ok(x) => {
-                    if nvr > 0 {
-                        let value_slice: &mut [f64] = unsafe { std::slice::from_raw_parts_mut(value, nvr) };
-                        let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
+                if vr.is_null() {
+                    return instance.fail("fmi2GetReal", "Null pointer passed for vr");
+                }
 
-                        for i in 0..nvr {
-                            value_slice[i] = x.get_real_by_value_reference(reference_slice[i] as u64).expect("fmi2GetReal: Unknown value reference");
-                        }
+                let value_slice: &mut [f64] = unsafe { std::slice::from_raw_parts_mut(value, nvr) };
+                let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
 
-                        fmi2Status_fmi2OK
-                    } else {
-                        fmi2Status_fmi2Error
+                for i in 0..nvr {
+                    match instance.model.get_real_by_value_reference(reference_slice[i] as u64) {
+                        Ok(v) => value_slice[i] = v,
+                        Err(e) => return instance.report("fmi2GetReal", &e),
                     }
                 }
-                Err(_) => fmi2Status_fmi2Error,
-            }
+
+                fmi2Status_fmi2OK
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::GetReal, body) }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2GetInteger(
             c: fmi2Component,
             vr: *const fmi2ValueReference,
             nvr: usize,
             value: *mut fmi2Integer,
         ) -> fmi2Status {
-            assert!(
-                std::ptr::null() != c as *mut c_void,
-                "fmi2GetInteger: Null pointer passed"
-            );
-
-            assert!(
-                value != std::ptr::null_mut(),
-                "fmi2GetInteger: Null pointer passed"
-            );
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                if nvr == 0 {
+                    return fmi2Status_fmi2OK;
+                }
 
-            assert!(
-                vr != std::ptr::null_mut(),
-                "fmi2GetInteger: Null pointer passed"
-            );
+                if value.is_null() {
+                    return instance.fail("fmi2GetInteger", "Null pointer passed for value");
+                }
 
-            match #model_name::from_c_ptr(c as *mut c_void) {
-                Ok(x) => {
-                    if nvr > 0 {
-                        let value_slice: &mut [i32] = unsafe { std::slice::from_raw_parts_mut(value, nvr) };
-                        let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
+                if vr.is_null() {
+                    return instance.fail("fmi2GetInteger", "Null pointer passed for vr");
+                }
 
-                        for i in 0..nvr {
-                            value_slice[i] = x.get_integer_by_value_reference(reference_slice[i] as u64).expect("fmi2GetInteger: Unknown value reference") as i32;
-                        }
+                let value_slice: &mut [i32] = unsafe { std::slice::from_raw_parts_mut(value, nvr) };
+                let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
 
-                        fmi2Status_fmi2OK
-                    } else {
-                        fmi2Status_fmi2Error
+                for i in 0..nvr {
+                    match instance.model.get_integer_by_value_reference(reference_slice[i] as u64) {
+                        Ok(v) => value_slice[i] = v,
+                        Err(e) => return instance.report("fmi2GetInteger", &e),
                     }
                 }
-                Err(_) => fmi2Status_fmi2Error,
-            }
+
+                fmi2Status_fmi2OK
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::GetInteger, body) }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2GetBoolean(
             c: fmi2Component,
             vr: *const fmi2ValueReference,
             nvr: usize,
             value: *mut fmi2Boolean,
         ) -> fmi2Status {
-            assert!(
-                std::ptr::null() != c as *mut c_void,
-                "fmi2GetInteger: Null pointer passed"
-            );
-
-            assert!(
-                value != std::ptr::null_mut(),
-                "fmi2GetInteger: Null pointer passed"
-            );
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                if nvr == 0 {
+                    return fmi2Status_fmi2OK;
+                }
 
-            assert!(
-                vr != std::ptr::null_mut(),
-                "fmi2GetInteger: Null pointer passed"
-            );
+                if value.is_null() {
+                    return instance.fail("fmi2GetBoolean", "Null pointer passed for value");
+                }
 
-            match #model_name::from_c_ptr(c as *mut c_void) {
-                Ok(x) => {
-                    if nvr > 0 {
-                        let value_slice: &mut [fmi2Boolean] = unsafe { std::slice::from_raw_parts_mut(value, nvr) };
-                        let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
+                if vr.is_null() {
+                    return instance.fail("fmi2GetBoolean", "Null pointer passed for vr");
+                }
 
-                        for i in 0..nvr {
-                            value_slice[i] = x.get_bool_by_value_reference(reference_slice[i] as u64).expect("fmi2GetInteger: Unknown value reference") as fmi2Boolean;
-                        }
+                let value_slice: &mut [fmi2Boolean] = unsafe { std::slice::from_raw_parts_mut(value, nvr) };
+                let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
 
-                        fmi2Status_fmi2OK
-                    } else {
-                        fmi2Status_fmi2Error
+                for i in 0..nvr {
+                    match instance.model.get_bool_by_value_reference(reference_slice[i] as u64) {
+                        Ok(v) => value_slice[i] = v as fmi2Boolean,
+                        Err(e) => return instance.report("fmi2GetBoolean", &e),
                     }
                 }
-                Err(_) => fmi2Status_fmi2Error,
-            }
+
+                fmi2Status_fmi2OK
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::GetBoolean, body) }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2GetString(
-            _c: fmi2Component,
-            _vr: *const fmi2ValueReference,
-            _nvr: usize,
-            _value: *mut fmi2String,
+            c: fmi2Component,
+            vr: *const fmi2ValueReference,
+            nvr: usize,
+            value: *mut fmi2String,
         ) -> fmi2Status {
-            fmi2Status_fmi2Error
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                if nvr == 0 {
+                    return fmi2Status_fmi2OK;
+                }
+
+                if value.is_null() {
+                    return instance.fail("fmi2GetString", "Null pointer passed for value");
+                }
+
+                if vr.is_null() {
+                    return instance.fail("fmi2GetString", "Null pointer passed for vr");
+                }
+
+                let value_slice: &mut [fmi2String] = unsafe { std::slice::from_raw_parts_mut(value, nvr) };
+                let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
+
+                let mut strings: Vec<CString> = Vec::with_capacity(nvr);
+                for i in 0..nvr {
+                    match instance.model.get_string_by_value_reference(reference_slice[i] as u64).map(CString::new) {
+                        Ok(Ok(s)) => strings.push(s),
+                        Ok(Err(_)) => {
+                            return instance.fail("fmi2GetString", &format!(
+                                "String contains a nul byte: {}",
+                                reference_slice[i]
+                            ));
+                        }
+                        Err(e) => return instance.report("fmi2GetString", &e),
+                    }
+                }
+
+                // The strings must stay valid until the next call on this instance
+                for (v, s) in value_slice.iter_mut().zip(instance.hold_strings(strings)) {
+                    *v = s;
+                }
+
+                fmi2Status_fmi2OK
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::GetString, body) }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2SetReal(
             c: fmi2Component,
             vr: *const fmi2ValueReference,
             nvr: usize,
             value: *const fmi2Real,
         ) -> fmi2Status {
-            assert!(
-                std::ptr::null() != c as *mut c_void,
-                "fmi2SetReal: Null pointer passed"
-            );
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                if nvr == 0 {
+                    return fmi2Status_fmi2OK;
+                }
 
-            assert!(
-                value != std::ptr::null_mut(),
-                "fmi2SetReal: Null pointer passed"
-            );
+                if value.is_null() {
+                    return instance.fail("fmi2SetReal", "Null pointer passed for value");
+                }
 
-            assert!(
-                vr != std::ptr::null_mut(),
-                "fmi2SetReal: Null pointer passed"
-            );
+                if vr.is_null() {
+                    return instance.fail("fmi2SetReal", "Null pointer passed for vr");
+                }
 
-            match #model_name::from_c_ptr(c as *mut c_void) {
-                Ok(x) => {
-                    if nvr > 0 {
-                        let value_slice: &[f64] = unsafe { std::slice::from_raw_parts(value, nvr) };
-                        let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
-                
-                        for i in 0..nvr {
-                            if x.set_real_by_value_reference(reference_slice[i] as u64, value_slice[i]).is_err() {
-                                println!(
-                                    "fmi2GetReal: Unknown value reference: {}",
-                                    reference_slice[i]
-                                );
-                                return fmi2Status_fmi2Error;
+                let value_slice: &[f64] = unsafe { std::slice::from_raw_parts(value, nvr) };
+                let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
 
-                            }
+                if let Err(e) = instance.check_settable(reference_slice) {
+                    return instance.fail("fmi2SetReal", &e.message);
+                }
+
+                let mut status = fmi2Status_fmi2OK;
+                for i in 0..nvr {
+                    if let Err(e) = instance.model.set_real_by_value_reference(reference_slice[i] as u64, value_slice[i]) {
+                        status = instance.report("fmi2SetReal", &e);
+                        if e.severity > fmi2::Severity::Warning {
+                            return status;
                         }
-                        fmi2Status_fmi2OK
-                    } else {
-                        fmi2Status_fmi2Error
                     }
                 }
-                Err(_) => fmi2Status_fmi2Error,
-            }
-            // print!("fmi2SetReal: ");
-            // println!("{:?}", x);
-            // println!("{:?}, {:?}, {:?}", vr, nvr, value);
-
+                status
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::SetReal, body) }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2SetInteger(
             c: fmi2Component,
             vr: *const fmi2ValueReference,
             nvr: usize,
             value: *const fmi2Integer,
         ) -> fmi2Status {
-            assert!(
-                std::ptr::null() != c as *mut c_void,
-                "fmi2SetInteger: Null pointer passed"
-            );
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                if nvr == 0 {
+                    return fmi2Status_fmi2OK;
+                }
 
-            assert!(
-                value != std::ptr::null_mut(),
-                "fmi2SetInteger: Null pointer passed"
-            );
+                if value.is_null() {
+                    return instance.fail("fmi2SetInteger", "Null pointer passed for value");
+                }
 
-            assert!(
-                vr != std::ptr::null_mut(),
-                "fmi2SetInteger: Null pointer passed"
-            );
+                if vr.is_null() {
+                    return instance.fail("fmi2SetInteger", "Null pointer passed for vr");
+                }
 
-            match #model_name::from_c_ptr(c as *mut c_void) {
-                Ok(x) => {
-                    if nvr > 0 {
-                        let value_slice: &[fmi2Integer] = unsafe { std::slice::from_raw_parts(value, nvr) };
-                        let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
-                
-                        for i in 0..nvr {
-                            if x.set_integer_by_value_reference(reference_slice[i] as u64, value_slice[i] as i64).is_err() {
-                                println!(
-                                    "fmi2GetReal: Unknown value reference: {}",
-                                    reference_slice[i]
-                                );
-                                return fmi2Status_fmi2Error;
+                let value_slice: &[fmi2Integer] = unsafe { std::slice::from_raw_parts(value, nvr) };
+                let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
 
-                            }
+                if let Err(e) = instance.check_settable(reference_slice) {
+                    return instance.fail("fmi2SetInteger", &e.message);
+                }
+
+                let mut status = fmi2Status_fmi2OK;
+                for i in 0..nvr {
+                    if let Err(e) = instance.model.set_integer_by_value_reference(reference_slice[i] as u64, value_slice[i]) {
+                        status = instance.report("fmi2SetInteger", &e);
+                        if e.severity > fmi2::Severity::Warning {
+                            return status;
                         }
-                        fmi2Status_fmi2OK
-                    } else {
-                        fmi2Status_fmi2Error
                     }
                 }
-                Err(_) => fmi2Status_fmi2Error,
-            }
-            // print!("fmi2SetReal: ");
-            // println!("{:?}", x);
-            // println!("{:?}, {:?}, {:?}", vr, nvr, value);
-
+                status
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::SetInteger, body) }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2SetBoolean(
             c: fmi2Component,
             vr: *const fmi2ValueReference,
             nvr: usize,
             value: *const fmi2Boolean,
         ) -> fmi2Status {
-            assert!(
-                std::ptr::null() != c as *mut c_void,
-                "fmi2SetBoolean: Null pointer passed"
-            );
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                if nvr == 0 {
+                    return fmi2Status_fmi2OK;
+                }
 
-            assert!(
-                value != std::ptr::null_mut(),
-                "fmi2SetBoolean: Null pointer passed"
-            );
+                if value.is_null() {
+                    return instance.fail("fmi2SetBoolean", "Null pointer passed for value");
+                }
 
-            assert!(
-                vr != std::ptr::null_mut(),
-                "fmi2SetBoolean: Null pointer passed"
-            );
+                if vr.is_null() {
+                    return instance.fail("fmi2SetBoolean", "Null pointer passed for vr");
+                }
 
-            match #model_name::from_c_ptr(c as *mut c_void) {
-                Ok(x) => {
-                    if nvr > 0 {
-                        let value_slice: &[fmi2Integer] = unsafe { std::slice::from_raw_parts(value, nvr) };
-                        let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
-                
-                        for i in 0..nvr {
-                            let val = value_slice[i] == fmi2True as fmi2Boolean;
-                            if x.set_bool_by_value_reference(reference_slice[i] as u64, val).is_err() {
-                                println!(
-                                    "fmi2GetReal: Unknown value reference: {}",
-                                    reference_slice[i]
-                                );
-                                return fmi2Status_fmi2Error;
+                let value_slice: &[fmi2Integer] = unsafe { std::slice::from_raw_parts(value, nvr) };
+                let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
 
-                            }
+                if let Err(e) = instance.check_settable(reference_slice) {
+                    return instance.fail("fmi2SetBoolean", &e.message);
+                }
+
+                let mut status = fmi2Status_fmi2OK;
+                for i in 0..nvr {
+                    let val = value_slice[i] == fmi2True as fmi2Boolean;
+                    if let Err(e) = instance.model.set_bool_by_value_reference(reference_slice[i] as u64, val) {
+                        status = instance.report("fmi2SetBoolean", &e);
+                        if e.severity > fmi2::Severity::Warning {
+                            return status;
                         }
-                        fmi2Status_fmi2OK
-                    } else {
-                        fmi2Status_fmi2Error
                     }
                 }
-                Err(_) => fmi2Status_fmi2Error,
-            }
-            // print!("fmi2SetReal: ");
-            // println!("{:?}", x);
-            // println!("{:?}, {:?}, {:?}", vr, nvr, value);
-
+                status
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::SetBoolean, body) }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2SetString(
-            _c: fmi2Component,
-            _vr: *const fmi2ValueReference,
-            _nvr: usize,
-            _value: *const fmi2String,
+            c: fmi2Component,
+            vr: *const fmi2ValueReference,
+            nvr: usize,
+            value: *const fmi2String,
         ) -> fmi2Status {
-            fmi2Status_fmi2Error
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                if nvr == 0 {
+                    return fmi2Status_fmi2OK;
+                }
+
+                if value.is_null() {
+                    return instance.fail("fmi2SetString", "Null pointer passed for value");
+                }
+
+                if vr.is_null() {
+                    return instance.fail("fmi2SetString", "Null pointer passed for vr");
+                }
+
+                let value_slice: &[fmi2String] = unsafe { std::slice::from_raw_parts(value, nvr) };
+                let reference_slice: &[fmi2ValueReference] = unsafe { std::slice::from_raw_parts(vr, nvr) };
+
+                if let Err(e) = instance.check_settable(reference_slice) {
+                    return instance.fail("fmi2SetString", &e.message);
+                }
+
+                let mut status = fmi2Status_fmi2OK;
+                for i in 0..nvr {
+                    if value_slice[i].is_null() {
+                        return instance.fail("fmi2SetString", &format!(
+                            "Null string passed for value reference: {}",
+                            reference_slice[i]
+                        ));
+                    }
+
+                    let val = match unsafe { CStr::from_ptr(value_slice[i]) }.to_str() {
+                        Ok(val) => val,
+                        Err(_) => {
+                            return instance.fail("fmi2SetString", &format!(
+                                "Invalid UTF-8 for value reference: {}",
+                                reference_slice[i]
+                            ));
+                        }
+                    };
+
+                    if let Err(e) = instance.model.set_string_by_value_reference(reference_slice[i] as u64, val) {
+                        status = instance.report("fmi2SetString", &e);
+                        if e.severity > fmi2::Severity::Warning {
+                            return status;
+                        }
+                    }
+                }
+                status
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::SetString, body) }
         }
 
         #[no_mangle]
-        pub extern "C" fn fmi2GetFMUstate(_c: fmi2Component, _FMUstate: *mut fmi2FMUstate) -> fmi2Status {
-            fmi2Status_fmi2Error
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
+        pub extern "C" fn fmi2GetFMUstate(c: fmi2Component, FMUstate: *mut fmi2FMUstate) -> fmi2Status {
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                if FMUstate.is_null() {
+                    return instance.fail("fmi2GetFMUstate", "Null pointer passed for FMUstate");
+                }
+
+                let ops = match #fmu_state_ops {
+                    Some(ops) => ops,
+                    None => {
+                        return instance.fail("fmi2GetFMUstate", &format!("{} does not implement FmuStateSnapshot", #model_name::model_name()));
+                    }
+                };
+
+                let x = &mut instance.model;
+                let previous = unsafe { *FMUstate };
+                if previous.is_null() {
+                    let snapshot = Box::new((ops.snapshot)(x));
+                    unsafe { *FMUstate = Box::into_raw(snapshot) as fmi2FMUstate };
+                } else {
+                    // The master hands back a state it got earlier, overwrite it in place
+                    let snapshot: &mut #model_name = unsafe { &mut *(previous as *mut #model_name) };
+                    (ops.restore)(snapshot, x);
+                }
+                fmi2Status_fmi2OK
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::GetFMUstate, body) }
         }
+
         #[no_mangle]
-        pub extern "C" fn fmi2SetFMUstate(_c: fmi2Component, _FMUstate: fmi2FMUstate) -> fmi2Status {
-            fmi2Status_fmi2Error
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
+        pub extern "C" fn fmi2SetFMUstate(c: fmi2Component, FMUstate: fmi2FMUstate) -> fmi2Status {
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                if FMUstate.is_null() {
+                    return instance.fail("fmi2SetFMUstate", "Null FMU state passed");
+                }
+
+                let ops = match #fmu_state_ops {
+                    Some(ops) => ops,
+                    None => {
+                        return instance.fail("fmi2SetFMUstate", &format!("{} does not implement FmuStateSnapshot", #model_name::model_name()));
+                    }
+                };
+
+                let snapshot: &#model_name = unsafe { &*(FMUstate as *const #model_name) };
+                (ops.restore)(&mut instance.model, snapshot);
+                // Rolling back a discarded step makes the model usable again
+                if instance.state == fmi2::ModelState::StepFailed {
+                    instance.state = fmi2::ModelState::StepComplete;
+                }
+                fmi2Status_fmi2OK
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::SetFMUstate, body) }
         }
+
         #[no_mangle]
-        pub extern "C" fn fmi2FreeFMUstate(_c: fmi2Component, _FMUstate: *mut fmi2FMUstate) -> fmi2Status {
-            fmi2Status_fmi2Error
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
+        pub extern "C" fn fmi2FreeFMUstate(c: fmi2Component, FMUstate: *mut fmi2FMUstate) -> fmi2Status {
+            let body = |_: &mut fmi2::Instance<#model_name>| {
+                if FMUstate.is_null() {
+                    return fmi2Status_fmi2OK;
+                }
+
+                let state = unsafe { *FMUstate };
+                if !state.is_null() {
+                    let snapshot = unsafe { Box::from_raw(state as *mut #model_name) };
+                    drop(snapshot);
+                    unsafe { *FMUstate = std::ptr::null_mut() };
+                }
+                fmi2Status_fmi2OK
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::FreeFMUstate, body) }
         }
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2SerializedFMUstateSize(
-            _c: fmi2Component,
-            _FMUstate: fmi2FMUstate,
-            _size: *mut usize,
+            c: fmi2Component,
+            FMUstate: fmi2FMUstate,
+            size: *mut usize,
         ) -> fmi2Status {
-            fmi2Status_fmi2Error
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                if size.is_null() {
+                    return instance.fail("fmi2SerializedFMUstateSize", "Null pointer passed for size");
+                }
+
+                if FMUstate.is_null() {
+                    return instance.fail("fmi2SerializedFMUstateSize", "Null FMU state passed");
+                }
+
+                let ops = match #fmu_state_serde_ops {
+                    Some(ops) => ops,
+                    None => {
+                        return instance.fail("fmi2SerializedFMUstateSize", &format!("{} does not implement Serialize and Deserialize", #model_name::model_name()));
+                    }
+                };
+
+                let snapshot: &#model_name = unsafe { &*(FMUstate as *const #model_name) };
+                match (ops.serialize)(#model_name::guid(), snapshot) {
+                    Ok(bytes) => {
+                        unsafe { *size = bytes.len() };
+                        fmi2Status_fmi2OK
+                    }
+                    Err(e) => instance.fail("fmi2SerializedFMUstateSize", &e.to_string()),
+                }
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::SerializedFMUstateSize, body) }
         }
+
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2SerializeFMUstate(
-            _c: fmi2Component,
-            _FMUstate: fmi2FMUstate,
-            _serializedState: *mut fmi2Byte,
-            _size: usize,
+            c: fmi2Component,
+            FMUstate: fmi2FMUstate,
+            serializedState: *mut fmi2Byte,
+            size: usize,
         ) -> fmi2Status {
-            fmi2Status_fmi2Error
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                if serializedState.is_null() {
+                    return instance.fail("fmi2SerializeFMUstate", "Null pointer passed for serializedState");
+                }
+
+                if FMUstate.is_null() {
+                    return instance.fail("fmi2SerializeFMUstate", "Null FMU state passed");
+                }
+
+                let ops = match #fmu_state_serde_ops {
+                    Some(ops) => ops,
+                    None => {
+                        return instance.fail("fmi2SerializeFMUstate", &format!("{} does not implement Serialize and Deserialize", #model_name::model_name()));
+                    }
+                };
+
+                let snapshot: &#model_name = unsafe { &*(FMUstate as *const #model_name) };
+                match (ops.serialize)(#model_name::guid(), snapshot) {
+                    Ok(bytes) => {
+                        if bytes.len() > size {
+                            return instance.fail("fmi2SerializeFMUstate", &format!(
+                                "Buffer of {} bytes too small, {} bytes needed",
+                                size,
+                                bytes.len()
+                            ));
+                        }
+
+                        let buffer: &mut [fmi2Byte] = unsafe { std::slice::from_raw_parts_mut(serializedState, bytes.len()) };
+                        for (b, byte) in buffer.iter_mut().zip(bytes.iter()) {
+                            *b = *byte as fmi2Byte;
+                        }
+                        fmi2Status_fmi2OK
+                    }
+                    Err(e) => instance.fail("fmi2SerializeFMUstate", &e.to_string()),
+                }
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::SerializeFMUstate, body) }
         }
+
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2DeSerializeFMUstate(
-            _c: fmi2Component,
-            _serializedState: *const fmi2Byte,
-            _size: usize,
-            _FMUstate: *mut fmi2FMUstate,
-        ) -> fmi2Status {
-            fmi2Status_fmi2Error
-        }
-        #[no_mangle]
-        pub extern "C" fn fmi2GetDirectionalDerivative(
-            _c: fmi2Component,
-            _vUnknown_ref: *const fmi2ValueReference,
-            _nUnknown: usize,
-            _vKnown_ref: *const fmi2ValueReference,
-            _nKnown: usize,
-            _dvKnown: *const fmi2Real,
-            _dvUnknown: *mut fmi2Real,
-        ) -> fmi2Status {
-            fmi2Status_fmi2Error
-        }
-        #[no_mangle]
-        pub extern "C" fn fmi2EnterEventMode(_c: fmi2Component) -> fmi2Status {
-            fmi2Status_fmi2Error
-        }
-        #[no_mangle]
-        pub extern "C" fn fmi2NewDiscreteStates(
-            _c: fmi2Component,
-            _fmi2eventInfo: *mut fmi2EventInfo,
-        ) -> fmi2Status {
-            fmi2Status_fmi2Error
-        }
-        #[no_mangle]
-        pub extern "C" fn fmi2EnterContinuousTimeMode(_c: fmi2Component) -> fmi2Status {
-            fmi2Status_fmi2Error
-        }
-        #[no_mangle]
-        pub extern "C" fn fmi2CompletedIntegratorStep(
-            _c: fmi2Component,
-            _noSetFMUStatePriorToCurrentPoint: fmi2Boolean,
-            _enterEventMode: *mut fmi2Boolean,
-            _terminateSimulation: *mut fmi2Boolean,
-        ) -> fmi2Status {
-            fmi2Status_fmi2Error
-        }
-        #[no_mangle]
-        pub extern "C" fn fmi2SetTime(_c: fmi2Component, _time: fmi2Real) -> fmi2Status {
-            fmi2Status_fmi2Error
-        }
-        #[no_mangle]
-        pub extern "C" fn fmi2SetContinuousStates(
-            _c: fmi2Component,
-            _x: *const fmi2Real,
-            _nx: usize,
-        ) -> fmi2Status {
-            fmi2Status_fmi2Error
-        }
-        #[no_mangle]
-        pub extern "C" fn fmi2GetDerivatives(
-            _c: fmi2Component,
-            _derivatives: *mut fmi2Real,
-            _nx: usize,
-        ) -> fmi2Status {
-            fmi2Status_fmi2Error
-        }
-        #[no_mangle]
-        pub extern "C" fn fmi2GetEventIndicators(
-            _c: fmi2Component,
-            _eventIndicators: *mut fmi2Real,
-            _ni: usize,
-        ) -> fmi2Status {
-            fmi2Status_fmi2Error
-        }
-        #[no_mangle]
-        pub extern "C" fn fmi2GetContinuousStates(
-            _c: fmi2Component,
-            _x: *mut fmi2Real,
-            _nx: usize,
-        ) -> fmi2Status {
-            fmi2Status_fmi2Error
-        }
-        #[no_mangle]
-        pub extern "C" fn fmi2GetNominalsOfContinuousStates(
-            _c: fmi2Component,
-            _x_nominal: *mut fmi2Real,
-            _nx: usize,
-        ) -> fmi2Status {
-            fmi2Status_fmi2Error
-        }
-        #[no_mangle]
-        pub extern "C" fn fmi2SetRealInputDerivatives(
-            _c: fmi2Component,
-            _vr: *const fmi2ValueReference,
-            _nvr: usize,
-            _order: *const fmi2Integer,
-            _value: *const fmi2Real,
-        ) -> fmi2Status {
-            fmi2Status_fmi2Error
-        }
-        #[no_mangle]
-        pub extern "C" fn fmi2GetRealOutputDerivatives(
-            _c: fmi2Component,
-            _vr: *const fmi2ValueReference,
-            _nvr: usize,
-            _order: *const fmi2Integer,
-            _value: *mut fmi2Real,
+            c: fmi2Component,
+            serializedState: *const fmi2Byte,
+            size: usize,
+            FMUstate: *mut fmi2FMUstate,
         ) -> fmi2Status {
-            fmi2Status_fmi2Error
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                if serializedState.is_null() {
+                    return instance.fail("fmi2DeSerializeFMUstate", "Null pointer passed for serializedState");
+                }
+
+                if FMUstate.is_null() {
+                    return instance.fail("fmi2DeSerializeFMUstate", "Null pointer passed for FMUstate");
+                }
+
+                let ops = match #fmu_state_serde_ops {
+                    Some(ops) => ops,
+                    None => {
+                        return instance.fail("fmi2DeSerializeFMUstate", &format!("{} does not implement Serialize and Deserialize", #model_name::model_name()));
+                    }
+                };
+
+                let bytes: &[u8] = unsafe { std::slice::from_raw_parts(serializedState as *const u8, size) };
+                match (ops.deserialize)(#model_name::guid(), bytes) {
+                    Ok(snapshot) => {
+                        let previous = unsafe { *FMUstate };
+                        if !previous.is_null() {
+                            drop(unsafe { Box::from_raw(previous as *mut #model_name) });
+                        }
+                        unsafe { *FMUstate = Box::into_raw(Box::new(snapshot)) as fmi2FMUstate };
+                        fmi2Status_fmi2OK
+                    }
+                    Err(e) => instance.fail("fmi2DeSerializeFMUstate", &e.to_string()),
+                }
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::DeSerializeFMUstate, body) }
         }
+        #directional_derivative_export
+        #model_exchange_exports
+        #input_derivatives_export
+        #output_derivatives_export
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2DoStep(
             c: fmi2Component,
             currentCommunicationPoint: fmi2Real,
             communicationStepSize: fmi2Real,
             _noSetFMUStatePriorToCurrentPoint: fmi2Boolean,
         ) -> fmi2Status {
-            assert!(
-                std::ptr::null() != c as *mut c_void,
-                "fmi2GetReal: Null pointer passed"
-            );
-
-            // println!("fmi2DoStep: currentCommunicationPoint = {}, communicationStepSize = {}", currentCommunicationPoint, communicationStepSize);
-            let model: &mut #model_name = #model_name::from_c_ptr(c as *mut c_void).expect("fmi2DoStep: Failed to get model from c_ptr");
-            model.do_step(currentCommunicationPoint, communicationStepSize).map(|_| fmi2Status_fmi2OK).unwrap_or(fmi2Status_fmi2Error)
+            let body = |instance: &mut fmi2::Instance<#model_name>| {
+                instance.state = fmi2::ModelState::StepInProgress;
+                let result = instance.model.do_step(currentCommunicationPoint, communicationStepSize);
+                instance.transition("fmi2DoStep", result, fmi2::ModelState::StepComplete)
+            };
+            unsafe { fmi2::Instance::guard(c, fmi2::FmiFunction::DoStep, body) }
         }
 
         #[no_mangle]
-        pub extern "C" fn fmi2CancelStep(_c: fmi2Component) -> fmi2Status {
-            fmi2Status_fmi2Error
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
+        pub extern "C" fn fmi2CancelStep(c: fmi2Component) -> fmi2Status {
+            unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2CancelStep", "Not supported by this FMU") }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2GetStatus(
-            _c: fmi2Component,
+            c: fmi2Component,
             _s: fmi2StatusKind,
             _value: *mut fmi2Status,
         ) -> fmi2Status {
-            fmi2Status_fmi2Error
+            unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2GetStatus", "Not supported by this FMU") }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2GetRealStatus(
-            _c: fmi2Component,
+            c: fmi2Component,
             _s: fmi2StatusKind,
             _value: *mut fmi2Real,
         ) -> fmi2Status {
-            fmi2Status_fmi2Error
+            unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2GetRealStatus", "Not supported by this FMU") }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2GetIntegerStatus(
-            _c: fmi2Component,
+            c: fmi2Component,
             _s: fmi2StatusKind,
             _value: *mut fmi2Integer,
         ) -> fmi2Status {
-            fmi2Status_fmi2Error
+            unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2GetIntegerStatus", "Not supported by this FMU") }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2GetBooleanStatus(
-            _c: fmi2Component,
+            c: fmi2Component,
             _s: fmi2StatusKind,
             _value: *mut fmi2Boolean,
         ) -> fmi2Status {
-            fmi2Status_fmi2Error
+            unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2GetBooleanStatus", "Not supported by this FMU") }
         }
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern "C" fn fmi2GetStringStatus(
-            _c: fmi2Component,
+            c: fmi2Component,
             _s: fmi2StatusKind,
             _value: *mut fmi2String,
         ) -> fmi2Status {
-            fmi2Status_fmi2Error
+            unsafe { fmi2::Instance::<#model_name>::invalid_call(c, "fmi2GetStringStatus", "Not supported by this FMU") }
         }
 
         #[no_mangle]
-        pub fn get_model_description() -> String {
+        pub fn get_model_description() -> Result<String, fmi2::model_description::ModelError> {
             #model_name::to_model_description_xml()
         }
 
@@ -1244,3 +2992,134 @@ pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 
     output.into()
 }
+
+/// The text of the doc comment in `attrs`, with the lines joined by spaces.
+fn doc_comment(attrs: &[syn::Attribute]) -> String {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta {
+            syn::Meta::NameValue(syn::MetaNameValue {
+                value: syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(line), .. }),
+                ..
+            }) => Some(line.value().trim().to_string()),
+            _ => None,
+        })
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Debug, FromDeriveInput)]
+#[darling(supports(enum_unit), forward_attrs(doc))]
+struct FmiEnumerationReceiver {
+    ident: syn::Ident,
+    attrs: Vec<syn::Attribute>,
+    data: ast::Data<FmiEnumerationItemReceiver, ()>,
+}
+
+#[derive(Debug, FromVariant)]
+#[darling(forward_attrs(doc))]
+struct FmiEnumerationItemReceiver {
+    ident: syn::Ident,
+    attrs: Vec<syn::Attribute>,
+}
+
+/// Implement `fmi2::FmiEnumeration` for a fieldless enum. The items are the variants with their
+/// discriminants as values, the doc comments of the enum and its variants become the
+/// descriptions.
+#[proc_macro_derive(FmiEnumeration)]
+pub fn derive_enumeration(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input);
+    let enumeration = match FmiEnumerationReceiver::from_derive_input(&input) {
+        Ok(enumeration) => enumeration,
+        Err(e) => return e.write_errors().into(),
+    };
+
+    let name = &enumeration.ident;
+    let name_str = name.to_string();
+    let description = doc_comment(&enumeration.attrs);
+    let items = enumeration.data.take_enum().expect("Should never be struct");
+    let variants: Vec<&syn::Ident> = items.iter().map(|item| &item.ident).collect();
+    let variant_names: Vec<String> = variants.iter().map(|v| v.to_string()).collect();
+    let variant_descriptions: Vec<String> = items.iter().map(|item| doc_comment(&item.attrs)).collect();
+
+    let output = quote! {
+        impl fmi2::FmiEnumeration for #name {
+            const NAME: &'static str = #name_str;
+            const DESCRIPTION: &'static str = #description;
+            const ITEMS: &'static [fmi2::EnumerationItem] = &[
+                #(fmi2::EnumerationItem {
+                    name: #variant_names,
+                    value: #name::#variants as i32,
+                    description: #variant_descriptions,
+                },)*
+            ];
+
+            fn to_value(&self) -> i32 {
+                match self {
+                    #(#name::#variants => #name::#variants as i32,)*
+                }
+            }
+
+            fn from_value(value: i32) -> Option<Self> {
+                #(
+                    if value == #name::#variants as i32 {
+                        return Some(#name::#variants);
+                    }
+                )*
+                None
+            }
+        }
+    };
+
+    output.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fmi_types() {
+        let of = |ty: &str| FmiType::of(&syn::parse_str::<syn::Type>(ty).unwrap());
+
+        assert_eq!(of("f32"), Some(FmiType::Real));
+        assert_eq!(of("core::primitive::f64"), Some(FmiType::Real));
+        assert_eq!(of("u16"), Some(FmiType::Integer));
+        assert_eq!(of("::std::primitive::i64"), Some(FmiType::Integer));
+        assert_eq!(of("std::string::String"), Some(FmiType::String));
+        assert_eq!(of("bool"), Some(FmiType::Boolean));
+        assert_eq!(of("modes::Solver"), Some(FmiType::Enumeration));
+        assert_eq!(of("Vec<f64>"), None);
+        assert_eq!(of("&str"), None);
+    }
+
+    #[test]
+    fn test_allowed_initials() {
+        use Variability::*;
+
+        assert_eq!(Causality::Parameter.allowed_initials(Tunable), Some(&[Initial::Exact][..]));
+        assert_eq!(Causality::Parameter.allowed_initials(Continuous), None);
+        assert_eq!(Causality::Input.allowed_initials(Discrete), Some(&[][..]));
+        assert_eq!(Causality::Input.allowed_initials(Constant), None);
+        assert_eq!(Causality::Local.allowed_initials(Fixed).unwrap()[0], Initial::Calculated);
+        assert_eq!(Causality::CalculatedParameter.allowed_initials(Tunable).unwrap()[0], Initial::Calculated);
+        assert_eq!(Causality::CalculatedParameter.allowed_initials(Continuous), None);
+        assert_eq!(Causality::Output.allowed_initials(Continuous).unwrap().len(), 3);
+        assert_eq!(Causality::Independent.allowed_initials(Discrete), None);
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let parse = |expr: &str| Dependencies::from_expr(&syn::parse_str::<syn::Expr>(expr).unwrap());
+
+        assert_eq!(
+            parse(r#"["u", "inverter.k : fixed"]"#).unwrap(),
+            Dependencies(vec![("u".to_string(), DependencyKind::Dependent), ("inverter.k".to_string(), DependencyKind::Fixed)])
+        );
+        assert_eq!(parse("[]").unwrap(), Dependencies(vec![]));
+        assert!(parse(r#"["u: linear"]"#).is_err());
+        assert!(parse(r#""u""#).is_err());
+    }
+}
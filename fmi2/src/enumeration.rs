@@ -0,0 +1,48 @@
+//! Fieldless Rust enums exported as FMI Enumeration types.
+//!
+//! `#[derive(FmiEnumeration)]` implements `FmiEnumeration` for an enum. Fields of that type on
+//! an `FmiModelStructDerive` struct become Enumeration variables, which are read and written
+//! through `fmi2GetInteger` and `fmi2SetInteger`.
+
+use crate::model_description::{Item, SimpleType, SimpleTypeKind};
+
+/// An item of an Enumeration type, with the discriminant of the enum variant as its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnumerationItem {
+    pub name: &'static str,
+    pub value: i32,
+    pub description: &'static str,
+}
+
+pub trait FmiEnumeration
+where Self: Sized {
+    /// The name of the `<SimpleType>` in `<TypeDefinitions>`, used as the `declaredType` of the
+    /// variables.
+    const NAME: &'static str;
+    const DESCRIPTION: &'static str;
+    const ITEMS: &'static [EnumerationItem];
+
+    fn to_value(&self) -> i32;
+
+    /// The variant with the given value, `None` if there is none.
+    fn from_value(value: i32) -> Option<Self>;
+
+    /// The `<SimpleType>` of the model description.
+    fn type_definition() -> SimpleType {
+        SimpleType {
+            name: Self::NAME.to_string(),
+            description: Self::DESCRIPTION.to_string(),
+            kind: SimpleTypeKind::Enumeration {
+                quantity: None,
+                items: Self::ITEMS
+                    .iter()
+                    .map(|item| Item {
+                        name: item.name.to_string(),
+                        value: item.value,
+                        description: item.description.to_string(),
+                    })
+                    .collect(),
+            },
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Errors reported by model code, and how they map onto `fmi2Status`.
+//!
+//! Model code returns `FmiResult` from `Steppable::do_step`, the setters and the Model Exchange
+//! callbacks. The generated exports turn an `FmiError` into the `fmi2Status` of its severity
+//! and send its message to the logger of the instance.
+
+use crate::logger::Category;
+use crate::serialization::SerializationError;
+use fmi2_sys::*;
+use std::fmt;
+
+/// How bad an `FmiError` is. Each severity is one of the non-OK values of `fmi2Status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The call succeeded, but something is off, like an input that was clipped to its range.
+    Warning,
+    /// The call could not be completed, but the instance can continue, e.g. a step that has to
+    /// be repeated with a smaller step size.
+    Discard,
+    /// The call failed and the instance can only be inspected, reset or freed.
+    Error,
+    /// The model is broken beyond repair, no further calls may be made.
+    Fatal,
+}
+
+impl Severity {
+    pub fn status(&self) -> fmi2Status {
+        match self {
+            Severity::Warning => fmi2Status_fmi2Warning,
+            Severity::Discard => fmi2Status_fmi2Discard,
+            Severity::Error => fmi2Status_fmi2Error,
+            Severity::Fatal => fmi2Status_fmi2Fatal,
+        }
+    }
+
+    /// The log category messages of this severity are reported under.
+    pub fn category(&self) -> Category {
+        match self {
+            Severity::Warning => Category::Warning,
+            Severity::Discard => Category::Discard,
+            Severity::Error => Category::Error,
+            Severity::Fatal => Category::Fatal,
+        }
+    }
+}
+
+/// What the generated setters do with a value outside of the `min` and `max` of a variable, set
+/// with `#[fmi_model(range_policy = "...")]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangePolicy {
+    /// Fail with `fmi2Error` and leave the variable unchanged.
+    Reject,
+    /// Set the variable anyway and return `fmi2Warning`.
+    Warn,
+}
+
+impl RangePolicy {
+    /// Check a value against the range of the variable `name`. With `Warn` an out-of-range value
+    /// gives `Ok(Some(warning))`, so that the caller can still set it. NaN is never within a
+    /// range, so it is rejected with either policy.
+    pub fn check(&self, name: impl fmt::Display, value: f64, min: Option<f64>, max: Option<f64>) -> FmiResult<Option<FmiError>> {
+        if value.is_nan() && (min.is_some() || max.is_some()) {
+            return Err(FmiError::error(format!("{} = NaN is outside of its min and max", name)));
+        }
+        let message = match (min, max) {
+            (Some(min), _) if value < min => format!("{} = {} is below its min {}", name, value, min),
+            (_, Some(max)) if value > max => format!("{} = {} is above its max {}", name, value, max),
+            _ => return Ok(None),
+        };
+
+        match self {
+            RangePolicy::Reject => Err(FmiError::error(message)),
+            RangePolicy::Warn => Ok(Some(FmiError::warning(message))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FmiError {
+    pub severity: Severity,
+    pub message: String,
+}
+
+pub type FmiResult<T = ()> = Result<T, FmiError>;
+
+impl FmiError {
+    pub fn new(severity: Severity, message: impl Into<String>) -> FmiError {
+        FmiError {
+            severity,
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> FmiError {
+        FmiError::new(Severity::Warning, message)
+    }
+
+    pub fn discard(message: impl Into<String>) -> FmiError {
+        FmiError::new(Severity::Discard, message)
+    }
+
+    pub fn error(message: impl Into<String>) -> FmiError {
+        FmiError::new(Severity::Error, message)
+    }
+
+    pub fn fatal(message: impl Into<String>) -> FmiError {
+        FmiError::new(Severity::Fatal, message)
+    }
+
+    pub fn invalid_value_reference(value_reference: u64) -> FmiError {
+        FmiError::error(format!("Invalid value reference {}", value_reference))
+    }
+
+    pub fn status(&self) -> fmi2Status {
+        self.severity.status()
+    }
+}
+
+impl fmt::Display for FmiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for FmiError {}
+
+impl From<SerializationError> for FmiError {
+    fn from(e: SerializationError) -> FmiError {
+        FmiError::error(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_severity_maps_onto_status() {
+        let step = |clip: bool| -> FmiResult<f64> {
+            if clip {
+                return Err(FmiError::warning("input clipped"));
+            }
+            Ok(1.0)
+        };
+
+        let e = step(true).unwrap_err();
+        assert_eq!(e.status(), fmi2Status_fmi2Warning);
+        assert_eq!(e.severity.category(), Category::Warning);
+        assert_eq!(e.to_string(), "input clipped");
+
+        assert_eq!(FmiError::discard("").status(), fmi2Status_fmi2Discard);
+        assert_eq!(FmiError::invalid_value_reference(7).status(), fmi2Status_fmi2Error);
+        assert_eq!(FmiError::fatal("").status(), fmi2Status_fmi2Fatal);
+        assert!(Severity::Warning < Severity::Error);
+    }
+
+    #[test]
+    fn test_range_policy() {
+        assert_eq!(RangePolicy::Reject.check("f", 1.0, Some(0.0), None), Ok(None));
+        assert_eq!(
+            RangePolicy::Reject.check("f", -1.0, Some(0.0), None),
+            Err(FmiError::error("f = -1 is below its min 0"))
+        );
+        assert_eq!(
+            RangePolicy::Warn.check("f", 3.0, None, Some(2.5)),
+            Ok(Some(FmiError::warning("f = 3 is above its max 2.5")))
+        );
+        assert_eq!(
+            RangePolicy::Warn.check("f", f64::NAN, Some(0.0), None),
+            Err(FmiError::error("f = NaN is outside of its min and max"))
+        );
+        assert_eq!(RangePolicy::Reject.check("f", f64::NAN, None, None), Ok(None));
+    }
+}
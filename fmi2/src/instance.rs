@@ -0,0 +1,448 @@
+//! The FMU instance behind an `fmi2Component`.
+//!
+//! `fmi2Instantiate` boxes an `Instance` around the user model and hands out a pointer to it as
+//! the component. Everything the FMI interface needs to keep track of lives here, so the model
+//! struct only has to contain the model itself.
+
+use crate::logger::{Category, CurrentLogger, Logger};
+use crate::{Causality, FmiError, FmiModelStructDerive, FmiResult, Initial, Severity, Variability};
+use fmi2_sys::*;
+use std::any::Any;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// Where an instance is in its lifecycle, following the state machines of the FMI 2.0 standard
+/// for Co-Simulation and Model Exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelState {
+    Instantiated,
+    InitializationMode,
+    /// Model Exchange only.
+    EventMode,
+    /// Model Exchange only.
+    ContinuousTimeMode,
+    /// Co-Simulation only.
+    StepComplete,
+    /// Co-Simulation only, while `fmi2DoStep` is running.
+    StepInProgress,
+    /// Co-Simulation only, after `fmi2DoStep` returned `fmi2Discard`. The master can inspect
+    /// the outputs, restore an earlier FMU state or terminate.
+    StepFailed,
+    Terminated,
+    Error,
+}
+
+use ModelState::*;
+
+/// The FMI functions that operate on an instance, with the states they may be called in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FmiFunction {
+    SetDebugLogging,
+    Reset,
+    FreeInstance,
+    SetupExperiment,
+    EnterInitializationMode,
+    ExitInitializationMode,
+    Terminate,
+    GetReal,
+    GetInteger,
+    GetBoolean,
+    GetString,
+    SetReal,
+    SetInteger,
+    SetBoolean,
+    SetString,
+    GetFMUstate,
+    SetFMUstate,
+    FreeFMUstate,
+    SerializedFMUstateSize,
+    SerializeFMUstate,
+    DeSerializeFMUstate,
+    GetDirectionalDerivative,
+    SetRealInputDerivatives,
+    GetRealOutputDerivatives,
+    DoStep,
+    EnterEventMode,
+    NewDiscreteStates,
+    EnterContinuousTimeMode,
+    CompletedIntegratorStep,
+    SetTime,
+    SetContinuousStates,
+    GetDerivatives,
+    GetEventIndicators,
+    GetContinuousStates,
+    GetNominalsOfContinuousStates,
+}
+
+impl FmiFunction {
+    /// The name of the C function, which is also used in log messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FmiFunction::SetDebugLogging => "fmi2SetDebugLogging",
+            FmiFunction::Reset => "fmi2Reset",
+            FmiFunction::FreeInstance => "fmi2FreeInstance",
+            FmiFunction::SetupExperiment => "fmi2SetupExperiment",
+            FmiFunction::EnterInitializationMode => "fmi2EnterInitializationMode",
+            FmiFunction::ExitInitializationMode => "fmi2ExitInitializationMode",
+            FmiFunction::Terminate => "fmi2Terminate",
+            FmiFunction::GetReal => "fmi2GetReal",
+            FmiFunction::GetInteger => "fmi2GetInteger",
+            FmiFunction::GetBoolean => "fmi2GetBoolean",
+            FmiFunction::GetString => "fmi2GetString",
+            FmiFunction::SetReal => "fmi2SetReal",
+            FmiFunction::SetInteger => "fmi2SetInteger",
+            FmiFunction::SetBoolean => "fmi2SetBoolean",
+            FmiFunction::SetString => "fmi2SetString",
+            FmiFunction::GetFMUstate => "fmi2GetFMUstate",
+            FmiFunction::SetFMUstate => "fmi2SetFMUstate",
+            FmiFunction::FreeFMUstate => "fmi2FreeFMUstate",
+            FmiFunction::SerializedFMUstateSize => "fmi2SerializedFMUstateSize",
+            FmiFunction::SerializeFMUstate => "fmi2SerializeFMUstate",
+            FmiFunction::DeSerializeFMUstate => "fmi2DeSerializeFMUstate",
+            FmiFunction::GetDirectionalDerivative => "fmi2GetDirectionalDerivative",
+            FmiFunction::SetRealInputDerivatives => "fmi2SetRealInputDerivatives",
+            FmiFunction::GetRealOutputDerivatives => "fmi2GetRealOutputDerivatives",
+            FmiFunction::DoStep => "fmi2DoStep",
+            FmiFunction::EnterEventMode => "fmi2EnterEventMode",
+            FmiFunction::NewDiscreteStates => "fmi2NewDiscreteStates",
+            FmiFunction::EnterContinuousTimeMode => "fmi2EnterContinuousTimeMode",
+            FmiFunction::CompletedIntegratorStep => "fmi2CompletedIntegratorStep",
+            FmiFunction::SetTime => "fmi2SetTime",
+            FmiFunction::SetContinuousStates => "fmi2SetContinuousStates",
+            FmiFunction::GetDerivatives => "fmi2GetDerivatives",
+            FmiFunction::GetEventIndicators => "fmi2GetEventIndicators",
+            FmiFunction::GetContinuousStates => "fmi2GetContinuousStates",
+            FmiFunction::GetNominalsOfContinuousStates => "fmi2GetNominalsOfContinuousStates",
+        }
+    }
+
+    /// The states in which the function may be called.
+    pub fn allowed_states(&self) -> &'static [ModelState] {
+        use FmiFunction::*;
+        match self {
+            SetDebugLogging | Reset | FreeInstance => &[
+                Instantiated, InitializationMode, EventMode, ContinuousTimeMode, StepComplete,
+                StepInProgress, StepFailed, Terminated, Error,
+            ],
+            SetupExperiment | EnterInitializationMode => &[Instantiated],
+            ExitInitializationMode => &[InitializationMode],
+            Terminate => &[EventMode, ContinuousTimeMode, StepComplete, StepFailed],
+            GetReal | GetInteger | GetBoolean | GetString | GetDirectionalDerivative | GetDerivatives
+            | GetEventIndicators | GetContinuousStates => &[
+                InitializationMode, EventMode, ContinuousTimeMode, StepComplete, StepFailed, Terminated,
+                Error,
+            ],
+            GetNominalsOfContinuousStates => &[
+                Instantiated, InitializationMode, EventMode, ContinuousTimeMode, StepComplete, Terminated,
+                Error,
+            ],
+            SetReal | SetInteger | SetBoolean | SetString => &[
+                Instantiated, InitializationMode, EventMode, ContinuousTimeMode, StepComplete,
+            ],
+            GetFMUstate | SetFMUstate | FreeFMUstate | SerializedFMUstateSize | SerializeFMUstate
+            | DeSerializeFMUstate => &[
+                Instantiated, InitializationMode, EventMode, ContinuousTimeMode, StepComplete, StepFailed,
+                Terminated, Error,
+            ],
+            SetRealInputDerivatives => &[Instantiated, InitializationMode, StepComplete],
+            GetRealOutputDerivatives => &[StepComplete, StepFailed, Terminated, Error],
+            DoStep => &[StepComplete],
+            EnterEventMode | CompletedIntegratorStep | SetContinuousStates => &[ContinuousTimeMode],
+            NewDiscreteStates | EnterContinuousTimeMode => &[EventMode],
+            SetTime => &[EventMode, ContinuousTimeMode],
+        }
+    }
+}
+
+impl ModelState {
+    /// Whether a variable may be set in this state, based on its causality, variability and
+    /// initial attribute.
+    pub fn can_set(&self, causality: Causality, variability: Variability, initial: Option<Initial>) -> bool {
+        if variability == Variability::Constant {
+            return false;
+        }
+
+        match self {
+            Instantiated => matches!(initial, Some(Initial::Exact) | Some(Initial::Approx)) || causality == Causality::Input,
+            InitializationMode => initial == Some(Initial::Exact) || causality == Causality::Input,
+            EventMode | StepComplete => {
+                causality == Causality::Input
+                    || (causality == Causality::Parameter && variability == Variability::Tunable)
+            }
+            ContinuousTimeMode => causality == Causality::Input && variability == Variability::Continuous,
+            StepInProgress | StepFailed | Terminated | Error => false,
+        }
+    }
+}
+
+/// The arguments of `fmi2SetupExperiment`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExperimentSetup {
+    pub tolerance: Option<f64>,
+    pub start_time: f64,
+    pub stop_time: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct Instance<M> {
+    pub instance_name: String,
+    pub guid: String,
+    pub fmu_type: fmi2Type,
+    pub callbacks: Option<fmi2CallbackFunctions>,
+    /// Holds the logging flags set by `fmi2Instantiate` and `fmi2SetDebugLogging`.
+    pub logger: Logger,
+    pub state: ModelState,
+    pub experiment: ExperimentSetup,
+    pub model: M,
+    /// The strings returned by the last `fmi2GetString`, see `hold_strings`.
+    strings: Vec<CString>,
+}
+
+impl<M> Instance<M> {
+    /// Create an instance from the arguments of `fmi2Instantiate`.
+    pub fn new(
+        instance_name: &str,
+        fmu_type: fmi2Type,
+        guid: &str,
+        callbacks: Option<fmi2CallbackFunctions>,
+        logging_on: bool,
+        model: M,
+    ) -> Instance<M> {
+        Instance {
+            instance_name: instance_name.to_string(),
+            guid: guid.to_string(),
+            fmu_type,
+            callbacks,
+            logger: Logger::new(instance_name, callbacks, logging_on),
+            state: ModelState::Instantiated,
+            experiment: ExperimentSetup::default(),
+            model,
+            strings: Vec::new(),
+        }
+    }
+
+    /// Get the instance behind the component `c_ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `c_ptr` must be null or a component returned by `fmi2Instantiate` for the model `M` that
+    /// hasn't been freed, and no other reference to the instance may be used while the returned
+    /// one is alive.
+    pub unsafe fn from_c_ptr<'a>(c_ptr: *mut c_void) -> FmiResult<&'a mut Instance<M>> {
+        if c_ptr.is_null() {
+            Err(FmiError::error("Null pointer passed for the component"))
+        } else {
+            Ok(&mut *(c_ptr as *mut Instance<M>))
+        }
+    }
+
+    /// Get the instance for a call of `function`. Calling it in a state that doesn't allow it is
+    /// an invalid call, see `invalid_call`.
+    ///
+    /// # Safety
+    ///
+    /// See `from_c_ptr`.
+    pub unsafe fn from_c_ptr_checked<'a>(c_ptr: *mut c_void, function: FmiFunction) -> FmiResult<&'a mut Instance<M>> {
+        let instance = Self::from_c_ptr(c_ptr)?;
+        if function.allowed_states().contains(&instance.state) {
+            Ok(instance)
+        } else {
+            let error = FmiError::error(format!("Not allowed in state {:?}", instance.state));
+            instance.report(function.name(), &error);
+            Err(error)
+        }
+    }
+
+    /// Log an error and put the instance in the error state, in which it can only be
+    /// inspected, reset or freed.
+    pub fn fail(&mut self, function: &str, message: &str) -> fmi2Status {
+        self.report(function, &FmiError::error(message))
+    }
+
+    /// Report an error returned by model code called from `function` and get its status.
+    ///
+    /// The message is logged under the category of its severity. Errors and fatal errors put
+    /// the instance in the error state, a discarded `fmi2DoStep` leaves it in `StepFailed`.
+    pub fn report(&mut self, function: &str, error: &FmiError) -> fmi2Status {
+        self.logger.log(error.severity.category(), &format!("{}: {}", function, error));
+        match error.severity {
+            Severity::Warning => {}
+            Severity::Discard => {
+                if self.state == StepInProgress {
+                    self.state = StepFailed;
+                }
+            }
+            Severity::Error | Severity::Fatal => self.state = ModelState::Error,
+        }
+        error.status()
+    }
+
+    /// The status of a call of `function` that ended with `result`, see `report`.
+    pub fn status(&mut self, function: &str, result: FmiResult) -> fmi2Status {
+        match result {
+            Ok(()) => fmi2Status_fmi2OK,
+            Err(e) => self.report(function, &e),
+        }
+    }
+
+    /// Like `status`, but move the instance to `next` first if the call went through, that is
+    /// when `result` is ok or only a warning.
+    pub fn transition(&mut self, function: &str, result: FmiResult, next: ModelState) -> fmi2Status {
+        if !matches!(&result, Err(e) if e.severity > Severity::Warning) {
+            self.state = next;
+        }
+        self.status(function, result)
+    }
+
+    /// Report an invalid call of `function` on the instance `c_ptr`, like a null pointer
+    /// argument, and put the instance in the error state.
+    ///
+    /// Every invalid call is handled like this: calls in a state that doesn't allow them, with
+    /// arguments that don't fit the model, or of functions the FMU doesn't support. They return
+    /// `fmi2Error`, after which the FMI 2.0 standard only allows to inspect, reset or free the
+    /// instance.
+    ///
+    /// # Safety
+    ///
+    /// See `from_c_ptr`.
+    pub unsafe fn invalid_call(c_ptr: *mut c_void, function: &str, message: &str) -> fmi2Status {
+        match Self::from_c_ptr(c_ptr) {
+            Ok(instance) => instance.fail(function, message),
+            Err(_) => fmi2Status_fmi2Error,
+        }
+    }
+
+    /// Run the body of `function` for the instance `c_ptr`, if `function` may be called in its
+    /// current state, see `from_c_ptr_checked`.
+    ///
+    /// A panic must not unwind into the importer, that would abort the whole process. Panics
+    /// are caught here instead, logged, and put the instance in the error state. The logger of
+    /// the instance is the current one while the body runs.
+    ///
+    /// # Safety
+    ///
+    /// See `from_c_ptr`.
+    pub unsafe fn guard(
+        c_ptr: *mut c_void,
+        function: FmiFunction,
+        body: impl FnOnce(&mut Instance<M>) -> fmi2Status,
+    ) -> fmi2Status {
+        let _logger = Self::enter(c_ptr);
+        let instance = match Self::from_c_ptr_checked(c_ptr, function) {
+            Ok(instance) => instance,
+            Err(_) => return fmi2Status_fmi2Error,
+        };
+        match catch_unwind(AssertUnwindSafe(|| body(instance))) {
+            Ok(status) => status,
+            // The body and its borrow of the instance are gone after the unwind
+            Err(payload) => {
+                Self::invalid_call(c_ptr, function.name(), &format!("Panicked: {}", panic_message(&payload)))
+            }
+        }
+    }
+
+    /// Log a message for the instance `c_ptr`, if it is one.
+    ///
+    /// # Safety
+    ///
+    /// See `from_c_ptr`.
+    pub unsafe fn log(c_ptr: *mut c_void, category: Category, message: &str) {
+        if let Ok(instance) = Self::from_c_ptr(c_ptr) {
+            instance.logger.log(category, message);
+        }
+    }
+
+    /// Make a copy of the logger of the instance `c_ptr` available to model code through
+    /// `FmiModelStructDerive::log` until the returned guard is dropped.
+    ///
+    /// # Safety
+    ///
+    /// See `from_c_ptr`.
+    pub unsafe fn enter(c_ptr: *mut c_void) -> CurrentLogger {
+        CurrentLogger::enter(Self::from_c_ptr(c_ptr).ok().map(|instance| &instance.logger))
+    }
+
+    /// Keep the strings returned by `fmi2GetString` alive and return pointers to them.
+    ///
+    /// The FMI 2.0 standard requires the returned `fmi2String`s to stay valid until the next
+    /// call on the same instance, so they are only dropped when they are replaced by the next
+    /// `fmi2GetString` or when the instance is freed.
+    pub fn hold_strings(&mut self, values: Vec<CString>) -> Vec<*const c_char> {
+        self.strings = values;
+        self.strings.iter().map(|s| s.as_ptr()).collect()
+    }
+}
+
+/// The message of a panic caught with `catch_unwind`.
+pub fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+impl<M: FmiModelStructDerive> Instance<M> {
+    /// Check that all `value_references` may be set in the current state, as required for the
+    /// `fmi2SetXXX` functions. Setting one that may not be set is an invalid call, see
+    /// `invalid_call`.
+    pub fn check_settable(&self, value_references: &[fmi2ValueReference]) -> FmiResult {
+        for vr in value_references.iter().map(|vr| *vr as u64) {
+            let settable = match (M::causality_by_value_reference(vr), M::variability_by_value_reference(vr)) {
+                (Some(causality), Some(variability)) => {
+                    self.state.can_set(causality, variability, M::initial_by_value_reference(vr))
+                }
+                _ => true, // Unknown value references are reported by the setter
+            };
+
+            if !settable {
+                return Err(FmiError::error(format!("Variable {} can not be set in state {:?}", vr, self.state)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variables_settable_per_state() {
+        let parameter = (Causality::Parameter, Variability::Fixed, Some(Initial::Exact));
+        let input = (Causality::Input, Variability::Continuous, None);
+        let output = (Causality::Output, Variability::Continuous, Some(Initial::Calculated));
+
+        let settable = |state: ModelState, (causality, variability, initial)| state.can_set(causality, variability, initial);
+
+        assert!(settable(Instantiated, parameter));
+        assert!(settable(InitializationMode, parameter));
+        assert!(!settable(StepComplete, parameter));
+        assert!(settable(Instantiated, input));
+        assert!(settable(StepComplete, input));
+        assert!(settable(ContinuousTimeMode, input));
+        assert!(!settable(Terminated, input));
+        assert!(!settable(InitializationMode, output));
+
+        assert!(FmiFunction::DoStep.allowed_states() == [StepComplete]);
+    }
+
+    #[test]
+    fn test_report_by_severity() {
+        let mut instance = Instance::new("instance", fmi2Type_fmi2CoSimulation, "{guid}", None, false, ());
+
+        instance.state = StepInProgress;
+        let status = instance.transition("fmi2DoStep", Err(FmiError::warning("clipped")), StepComplete);
+        assert_eq!((status, instance.state), (fmi2Status_fmi2Warning, StepComplete));
+
+        instance.state = StepInProgress;
+        let status = instance.transition("fmi2DoStep", Err(FmiError::discard("too large")), StepComplete);
+        assert_eq!((status, instance.state), (fmi2Status_fmi2Discard, StepFailed));
+
+        let status = instance.status("fmi2DoStep", Err(FmiError::fatal("diverged")));
+        assert_eq!((status, instance.state), (fmi2Status_fmi2Fatal, Error));
+    }
+}
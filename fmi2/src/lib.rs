@@ -1,47 +1,513 @@
-#[macro_use]
 pub extern crate fmi2_derive;
 
-#[derive(Debug)]
-pub enum FMIErrors { InvalidValueReference, Error }
-
-use std::os::raw::{c_char, c_void};
-
 pub mod derive {
     pub use fmi2_derive::*;
 }
 
+pub mod enumeration;
+pub mod error;
+pub mod instance;
+pub mod logger;
+pub mod model_description;
+pub mod serialization;
+pub mod units;
+
+pub use enumeration::{EnumerationItem, FmiEnumeration};
+pub use error::{FmiError, FmiResult, RangePolicy, Severity};
+pub use instance::{ExperimentSetup, FmiFunction, Instance, ModelState};
+use serialization::SerializationError;
+
+/// The causality attribute of a model variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Parameter,
+    CalculatedParameter,
+    Input,
+    Output,
+    Local,
+    Independent,
+}
+
+impl Causality {
+    pub const ALL: [Causality; 6] = [
+        Causality::Parameter,
+        Causality::CalculatedParameter,
+        Causality::Input,
+        Causality::Output,
+        Causality::Local,
+        Causality::Independent,
+    ];
+
+    /// The value of the attribute in the model description.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Causality::Parameter => "parameter",
+            Causality::CalculatedParameter => "calculatedParameter",
+            Causality::Input => "input",
+            Causality::Output => "output",
+            Causality::Local => "local",
+            Causality::Independent => "independent",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Causality> {
+        Causality::ALL.iter().copied().find(|v| v.name() == name)
+    }
+}
+
+/// The variability attribute of a model variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variability {
+    Constant,
+    Fixed,
+    Tunable,
+    Discrete,
+    Continuous,
+}
+
+impl Variability {
+    pub const ALL: [Variability; 5] = [
+        Variability::Constant,
+        Variability::Fixed,
+        Variability::Tunable,
+        Variability::Discrete,
+        Variability::Continuous,
+    ];
+
+    /// The value of the attribute in the model description.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Variability::Constant => "constant",
+            Variability::Fixed => "fixed",
+            Variability::Tunable => "tunable",
+            Variability::Discrete => "discrete",
+            Variability::Continuous => "continuous",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Variability> {
+        Variability::ALL.iter().copied().find(|v| v.name() == name)
+    }
+}
+
+/// The initial attribute of a model variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Initial {
+    Exact,
+    Approx,
+    Calculated,
+}
+
+impl Initial {
+    pub const ALL: [Initial; 3] = [
+        Initial::Exact,
+        Initial::Approx,
+        Initial::Calculated,
+    ];
+
+    /// The value of the attribute in the model description.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Initial::Exact => "exact",
+            Initial::Approx => "approx",
+            Initial::Calculated => "calculated",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Initial> {
+        Initial::ALL.iter().copied().find(|v| v.name() == name)
+    }
+}
+
 pub trait FmiModelStructDerive {
-    fn get_real_by_value_reference(self: &Self, value_reference: u64) -> Option<f64>;
-    fn get_bool_by_value_reference(self: &Self, value_reference: u64) -> Option<bool>;
-    fn get_integer_by_value_reference(self: &Self, value_reference: u64) -> Option<i64>;
+    /// Real variables can be `f64` or `f32` fields. Integer variables can be any of the Rust
+    /// integer types, values that don't fit into the 32 bit `fmi2Integer` (or into the field,
+    /// when setting) are reported as errors. Enumeration variables go through the integer
+    /// accessors too, setting a value that is not an item of the enumeration is an error.
+    fn get_real_by_value_reference(&self, value_reference: u64) -> FmiResult<f64>;
+    fn get_bool_by_value_reference(&self, value_reference: u64) -> FmiResult<bool>;
+    fn get_integer_by_value_reference(&self, value_reference: u64) -> FmiResult<i32>;
+    fn get_string_by_value_reference(&self, value_reference: u64) -> FmiResult<&str>;
+
+    fn set_real_by_value_reference(&mut self, value_reference: u64, value: f64) -> FmiResult ;
+    fn set_integer_by_value_reference(&mut self, value_reference: u64, value: i32) -> FmiResult ;
+    fn set_bool_by_value_reference(&mut self, value_reference: u64, value: bool) -> FmiResult ;
+    fn set_string_by_value_reference(&mut self, value_reference: u64, value: &str) -> FmiResult ;
 
-    fn set_real_by_value_reference(self: &mut Self, value_reference: u64, value: f64) -> Result<(),FMIErrors> ;
-    fn set_integer_by_value_reference(self: &mut Self, value_reference: u64, value: i64) -> Result<(),FMIErrors> ;
-    fn set_bool_by_value_reference(self: &mut Self, value_reference: u64, value: bool) -> Result<(),FMIErrors> ;
+    fn nominal_by_value_reference(value_reference: u64) -> Option<f64>;
+    fn causality_by_value_reference(value_reference: u64) -> Option<Causality>;
+    fn variability_by_value_reference(value_reference: u64) -> Option<Variability>;
+    fn initial_by_value_reference(value_reference: u64) -> Option<Initial>;
+
+    /// One more than the largest value reference of the model, flattened sub-structs included.
+    /// A model with a `#[fmi_variable(flatten)]` field places the value references of the
+    /// child after its own, at an offset that keeps them apart.
+    fn value_reference_span() -> u64;
+
+    /// The scalar variables of the model, with `prefix` in front of their names and `offset`
+    /// added to their value references. Start values are taken from `self`.
+    fn model_variables(&self, prefix: &str, offset: u64) -> Vec<model_description::ScalarVariable>;
+
+    /// Set the variables with a `starting_value` attribute to it. Called on the model after
+    /// `Instantiatable::instantiate`, see `instantiate_with_start_values`, and on
+    /// `Default::default()` for the model description.
+    fn set_start_values(&mut self);
+
+    /// The continuous states of flattened sub-structs come after the ones of the model itself.
+    fn number_of_continuous_states() -> usize;
+    fn number_of_event_indicators() -> usize;
+    fn get_continuous_states(&self, x: &mut [f64]);
+    fn set_continuous_states(&mut self, x: &[f64]);
+    fn get_derivatives(&self, dx: &mut [f64]);
+    fn get_nominals_of_continuous_states(&self, x_nominal: &mut [f64]);
     
     fn guid() -> &'static str;
     fn description() -> &'static str;
     fn model_name() -> &'static str;
-    fn to_model_description_xml() -> String;
+
+    /// The model description of the model, with the start values of `Default::default()` after
+    /// `set_start_values`.
+    fn model_description() -> Result<model_description::ModelDescription, model_description::ModelError>;
+
+    fn to_model_description_xml() -> Result<String, model_description::ModelError> {
+        Self::model_description().map(|model_description| model_description.to_xml())
+    }
+
+    /// Log a message through the logger of the instance the current FMI call is working on.
+    /// Messages are passed on verbatim, there is no need to escape `%`.
+    fn log(&self, category: logger::Category, message: &str) {
+        logger::log_current(category, message)
+    }
 }
 
 pub trait Steppable 
 where Self: Sized {
-    fn do_step(&mut self, time: f64, step_size: f64) -> Result<(), FMIErrors>;
+    /// Advance the model from `time` by `step_size`. Return `FmiError::warning` if the step
+    /// went through with something to report, or `FmiError::discard` if it could not be
+    /// completed and the master should retry with a smaller step.
+    fn do_step(&mut self, time: f64, step_size: f64) -> FmiResult ;
+}
+
+/// Models that can be exported for Model Exchange.
+///
+/// The continuous states and their derivatives are fields marked with
+/// `#[fmi_variable(derivative = "state")]`, the derive takes care of moving them in and out of
+/// the state vectors. The model only has to keep the derivative fields up to date.
+pub trait ContinuousModel {
+    /// Called by `fmi2SetTime` with the new independent variable.
+    fn set_time(&mut self, time: f64);
+
+    /// Compute the derivative fields (and the outputs) from the current time, states and inputs.
+    fn compute_derivatives(&mut self) -> FmiResult ;
+
+    /// Called by `fmi2CompletedIntegratorStep` after each accepted integrator step. `step_info`
+    /// starts out with no requests every call.
+    fn completed_integrator_step(&mut self, _step_info: &mut IntegratorStepInfo) -> FmiResult {
+        Ok(())
+    }
+}
+
+/// What the model asks for after an integrator step, reported back to the master by
+/// `fmi2CompletedIntegratorStep`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IntegratorStepInfo {
+    /// Ask the master to enter event mode, e.g. for a step event.
+    pub enter_event_mode: bool,
+    /// Ask the master to stop the simulation.
+    pub terminate_simulation: bool,
+}
+
+/// What happened during an event iteration, reported back to the master through the
+/// `fmi2EventInfo` of `fmi2NewDiscreteStates`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EventInfo {
+    /// Request another call of `fmi2NewDiscreteStates` (event iteration).
+    pub new_discrete_states_needed: bool,
+    /// Ask the master to stop the simulation.
+    pub terminate_simulation: bool,
+    pub nominals_of_continuous_states_changed: bool,
+    /// The continuous states were re-initialized, e.g. the velocity of a bouncing ball.
+    pub values_of_continuous_states_changed: bool,
+    /// The time of the next time event, if any.
+    pub next_event_time: Option<f64>,
+}
+
+/// State events, time events and discrete state updates of a Model Exchange model.
+///
+/// Every Model Exchange model has to implement this, models without events can use the
+/// defaults (`impl fmi2::EventModel for MyModel {}`). The number of event indicators is
+/// declared with `#[fmi_model(event_indicators = N)]`.
+pub trait EventModel {
+    /// Fill in the event indicators for the current time, states and inputs. A state event is
+    /// triggered when one of them changes sign.
+    fn event_indicators(&mut self, _indicators: &mut [f64]) -> FmiResult {
+        Ok(())
+    }
+
+    /// Called by `fmi2EnterEventMode`.
+    fn enter_event_mode(&mut self) -> FmiResult {
+        Ok(())
+    }
+
+    /// Called by `fmi2NewDiscreteStates` to update the discrete states at an event. `event_info`
+    /// starts out with no requests and no next time event every call.
+    fn update_discrete_states(&mut self, _event_info: &mut EventInfo) -> FmiResult {
+        Ok(())
+    }
+}
+
+/// Models that provide the directional derivatives of `fmi2GetDirectionalDerivative`
+/// analytically, enabled with `#[fmi_model(directional_derivatives = "analytic")]`.
+pub trait DirectionalDerivative {
+    /// Compute `dv_unknown = J * dv_known`, where `J` is the Jacobian of the `unknowns` with
+    /// respect to the `knowns`, both given as value references of Real variables.
+    fn directional_derivative(
+        &mut self,
+        unknowns: &[u64],
+        knowns: &[u64],
+        dv_known: &[f64],
+        dv_unknown: &mut [f64],
+    ) -> FmiResult ;
+}
+
+/// Co-Simulation models that interpolate their Real inputs over a step from their time
+/// derivatives, enabled with `#[fmi_model(can_interpolate_inputs = true)]`.
+pub trait InputDerivatives {
+    /// Set the `order`-th time derivative of the input `value_reference` at the current
+    /// communication point, for the next `Steppable::do_step`.
+    fn set_real_input_derivative(&mut self, value_reference: u64, order: i32, value: f64) -> FmiResult;
+}
+
+/// Co-Simulation models that provide the time derivatives of their Real outputs at the end of a
+/// step, up to `#[fmi_model(max_output_derivative_order = N)]`.
+pub trait OutputDerivatives {
+    fn get_real_output_derivative(&self, value_reference: u64, order: i32) -> FmiResult<f64>;
+}
+
+/// Directional derivative by forward differences, used for
+/// `#[fmi_model(directional_derivatives = "finite_difference")]`.
+///
+/// The knowns are perturbed along `dv_known` all at once, with a step of `relative_step` times
+/// the largest of the known values and their nominal values. Knowns and unknowns are read and
+/// written through the value reference accessors, `evaluate` brings the unknowns up to date
+/// after the knowns changed, e.g. `ContinuousModel::compute_derivatives`. The knowns are
+/// restored afterwards.
+pub fn finite_difference_directional_derivative<M>(
+    model: &mut M,
+    unknowns: &[u64],
+    knowns: &[u64],
+    dv_known: &[f64],
+    dv_unknown: &mut [f64],
+    relative_step: f64,
+    mut evaluate: impl FnMut(&mut M) -> FmiResult,
+) -> FmiResult
+where
+    M: FmiModelStructDerive,
+{
+    let get_reals = |model: &M, vrs: &[u64]| -> FmiResult<Vec<f64>> {
+        vrs.iter().map(|vr| model.get_real_by_value_reference(*vr)).collect()
+    };
+
+    let x0 = get_reals(model, knowns)?;
+    evaluate(model)?;
+    let y0 = get_reals(model, unknowns)?;
+
+    let direction = dv_known.iter().fold(0.0f64, |acc, dv| acc.max(dv.abs()));
+    if direction == 0.0 {
+        dv_unknown.iter_mut().for_each(|dv| *dv = 0.0);
+        return Ok(());
+    }
+
+    let scale = knowns.iter().zip(x0.iter()).fold(0.0f64, |acc, (vr, x)| {
+        let nominal = M::nominal_by_value_reference(*vr).unwrap_or(1.0).abs();
+        acc.max(x.abs()).max(nominal)
+    });
+    let h = relative_step * scale / direction;
+
+    for ((vr, x), dv) in knowns.iter().zip(x0.iter()).zip(dv_known.iter()) {
+        model.set_real_by_value_reference(*vr, x + h * dv)?;
+    }
+    let perturbed = evaluate(model).and_then(|_| get_reals(model, unknowns));
+
+    // Always restore the knowns, even if the perturbed evaluation failed
+    for (vr, x) in knowns.iter().zip(x0.iter()) {
+        model.set_real_by_value_reference(*vr, *x)?;
+    }
+    evaluate(model)?;
+
+    for ((dv, y1), y0) in dv_unknown.iter_mut().zip(perturbed?.iter()).zip(y0.iter()) {
+        *dv = (y1 - y0) / h;
+    }
+    Ok(())
+}
+
+/// Capture and restore the complete state of a model for `fmi2GetFMUstate` and `fmi2SetFMUstate`.
+///
+/// Every model that implements `Clone` gets this for free, models that can't be cloned can
+/// implement it by hand.
+///
+/// An FMU state only holds the model. The instance around it is not part of it: setting a state
+/// leaves the instance where it is in the lifecycle (a discarded step becomes a completed one),
+/// and the arguments of `fmi2SetupExperiment` stay as they were. Everything that has to be
+/// rolled back, like the current time, has to be a field of the model.
+pub trait FmuStateSnapshot
+where Self: Sized {
+    fn snapshot(&self) -> Self;
+    fn restore(&mut self, snapshot: &Self);
+}
+
+impl<T: Clone> FmuStateSnapshot for T {
+    fn snapshot(&self) -> Self {
+        self.clone()
+    }
+
+    fn restore(&mut self, snapshot: &Self) {
+        self.clone_from(snapshot)
+    }
+}
+
+/// The snapshot functions of a model that implements `FmuStateSnapshot`.
+pub struct FmuStateOps<T> {
+    pub snapshot: fn(&T) -> T,
+    pub restore: fn(&mut T, &T),
+}
+
+/// The (de)serialization functions of a model that implements serde's `Serialize` and
+/// `Deserialize`, used by `fmi2SerializeFMUstate` and `fmi2DeSerializeFMUstate`.
+pub struct FmuStateSerdeOps<T> {
+    pub serialize: fn(&str, &T) -> Result<Vec<u8>, SerializationError>,
+    pub deserialize: fn(&str, &[u8]) -> Result<T, SerializationError>,
+}
+
+/// Detect at the call site whether a type implements a trait, without requiring it to.
+///
+/// The generated exports need to know if a model implements e.g. `FmuStateSnapshot` to decide
+/// what to export and which capability flags to set. This uses autoref specialization: the
+/// impls for `&Probe<T>` are only picked when their bounds hold, otherwise method resolution
+/// falls back to the impls for `Probe<T>`.
+#[doc(hidden)]
+pub mod detect {
+    use super::*;
+    use std::marker::PhantomData;
+
+    pub struct Probe<T>(PhantomData<T>);
+
+    impl<T> Probe<T> {
+        pub fn new() -> Self {
+            Probe(PhantomData)
+        }
+    }
+
+    impl<T> Default for Probe<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    pub trait FmuStateSupported<T> {
+        fn fmu_state_ops(&self) -> Option<FmuStateOps<T>>;
+    }
+
+    impl<T: FmuStateSnapshot> FmuStateSupported<T> for &Probe<T> {
+        fn fmu_state_ops(&self) -> Option<FmuStateOps<T>> {
+            Some(FmuStateOps {
+                snapshot: T::snapshot,
+                restore: T::restore,
+            })
+        }
+    }
+
+    pub trait FmuStateUnsupported<T> {
+        fn fmu_state_ops(&self) -> Option<FmuStateOps<T>> {
+            None
+        }
+    }
+
+    impl<T> FmuStateUnsupported<T> for Probe<T> {}
+
+    pub trait FmuStateSerdeSupported<T> {
+        fn fmu_state_serde_ops(&self) -> Option<FmuStateSerdeOps<T>>;
+    }
+
+    impl<T: serde::Serialize + serde::de::DeserializeOwned> FmuStateSerdeSupported<T> for &Probe<T> {
+        fn fmu_state_serde_ops(&self) -> Option<FmuStateSerdeOps<T>> {
+            Some(FmuStateSerdeOps {
+                serialize: serialization::serialize_state::<T>,
+                deserialize: serialization::deserialize_state::<T>,
+            })
+        }
+    }
+
+    pub trait FmuStateSerdeUnsupported<T> {
+        fn fmu_state_serde_ops(&self) -> Option<FmuStateSerdeOps<T>> {
+            None
+        }
+    }
+
+    impl<T> FmuStateSerdeUnsupported<T> for Probe<T> {}
+}
+
+/// Get `Some(FmuStateOps)` if the given (concrete) model type implements `FmuStateSnapshot`.
+#[macro_export]
+macro_rules! fmu_state_ops {
+    ($model:ty) => {{
+        #[allow(unused_imports)]
+        use $crate::detect::{FmuStateSupported as _, FmuStateUnsupported as _};
+        (&&$crate::detect::Probe::<$model>::new()).fmu_state_ops()
+    }};
+}
+
+/// Get `Some(FmuStateSerdeOps)` if the given (concrete) model type implements serde's
+/// `Serialize` and `Deserialize`.
+#[macro_export]
+macro_rules! fmu_state_serde_ops {
+    ($model:ty) => {{
+        #[allow(unused_imports)]
+        use $crate::detect::{FmuStateSerdeSupported as _, FmuStateSerdeUnsupported as _};
+        (&&$crate::detect::Probe::<$model>::new()).fmu_state_serde_ops()
+    }};
 }
 
 pub trait Instantiatable 
 where Self: Sized + Default {
-    fn instantiate() -> Result<Self, FMIErrors> {
+    fn instantiate() -> FmiResult<Self> {
         Ok(Self::default())
     }
+}
 
-    fn from_c_ptr<'a>(c_ptr: *mut c_void) -> Result<&'a mut Self, FMIErrors> {
-        if c_ptr == std::ptr::null_mut() {
-            return Err(FMIErrors::InvalidValueReference);
-        } else {
-            let x: &mut Self = unsafe { &mut *(c_ptr as *mut Self) };
-            Ok(x)
-        }
+/// Instantiate a model and set its start values, how `fmi2Instantiate` and `fmi2Reset` create
+/// the model of an instance.
+pub fn instantiate_with_start_values<M>() -> FmiResult<M>
+where
+    M: Instantiatable + FmiModelStructDerive,
+{
+    let mut model = M::instantiate()?;
+    model.set_start_values();
+    Ok(model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::{CStr, CString};
+
+    struct NotClone;
+
+    #[test]
+    fn test_detect_fmu_state_support() {
+        assert!(fmu_state_ops!(String).is_some());
+        assert!(fmu_state_ops!(NotClone).is_none());
+    }
+
+    #[test]
+    fn test_held_strings_stay_valid() {
+        let mut instance = Instance::new("instance", fmi2_sys::fmi2Type_fmi2CoSimulation, "{guid}", None, false, ());
+
+        let pointers = instance.hold_strings(vec![CString::new("a").unwrap(), CString::new("bc").unwrap()]);
+        let values: Vec<&str> = pointers
+            .iter()
+            .map(|p| unsafe { CStr::from_ptr(*p) }.to_str().unwrap())
+            .collect();
+        assert_eq!(values, vec!["a", "bc"]);
     }
 }
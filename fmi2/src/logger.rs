@@ -0,0 +1,243 @@
+//! Safe wrapper around the `fmi2CallbackLogger` of the simulation environment.
+//!
+//! The logger callback is a C varargs function that treats the message as a printf format
+//! string, so every `%` in a message is escaped before it is handed over.
+//!
+//! Each `Instance` owns its `Logger`. The generated exports make the logger of the instance they
+//! are working on the current one, which lets model code log through
+//! `FmiModelStructDerive::log` without holding on to the callbacks itself.
+
+use fmi2_sys::*;
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::sync::Arc;
+
+/// The log categories defined by the FMI 2.0 standard, plus `FmiCalls` for tracing calls
+/// into the FMU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Events,
+    SingularLinearSystems,
+    NonlinearSystems,
+    DynamicStateSelection,
+    Warning,
+    Discard,
+    Error,
+    Fatal,
+    Pending,
+    FmiCalls,
+}
+
+impl Category {
+    pub const ALL: [Category; 10] = [
+        Category::Events,
+        Category::SingularLinearSystems,
+        Category::NonlinearSystems,
+        Category::DynamicStateSelection,
+        Category::Warning,
+        Category::Discard,
+        Category::Error,
+        Category::Fatal,
+        Category::Pending,
+        Category::FmiCalls,
+    ];
+
+    /// The name used in `<LogCategories>` and `fmi2SetDebugLogging`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Events => "logEvents",
+            Category::SingularLinearSystems => "logSingularLinearSystems",
+            Category::NonlinearSystems => "logNonlinearSystems",
+            Category::DynamicStateSelection => "logDynamicStateSelection",
+            Category::Warning => "logStatusWarning",
+            Category::Discard => "logStatusDiscard",
+            Category::Error => "logStatusError",
+            Category::Fatal => "logStatusFatal",
+            Category::Pending => "logStatusPending",
+            Category::FmiCalls => "logFmiCalls",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Category> {
+        Category::ALL.iter().copied().find(|c| c.name() == name)
+    }
+
+    /// The status reported to the logger together with messages of this category.
+    pub fn status(&self) -> fmi2Status {
+        match self {
+            Category::Warning => fmi2Status_fmi2Warning,
+            Category::Discard => fmi2Status_fmi2Discard,
+            Category::Error => fmi2Status_fmi2Error,
+            Category::Fatal => fmi2Status_fmi2Fatal,
+            Category::Pending => fmi2Status_fmi2Pending,
+            _ => fmi2Status_fmi2OK,
+        }
+    }
+
+    fn index(&self) -> usize {
+        Category::ALL.iter().position(|c| c == self).unwrap()
+    }
+}
+
+/// Escape a message so the printf-style logger prints it verbatim.
+pub fn escape(message: &str) -> String {
+    message.replace('%', "%%")
+}
+
+#[derive(Debug, Clone)]
+pub struct Logger {
+    instance_name: Arc<CStr>,
+    callbacks: Option<fmi2CallbackFunctions>,
+    enabled: [bool; Category::ALL.len()],
+}
+
+impl Logger {
+    /// Create a logger from the arguments of `fmi2Instantiate`. Without callbacks nothing is
+    /// logged.
+    pub fn new(instance_name: &str, callbacks: Option<fmi2CallbackFunctions>, logging_on: bool) -> Logger {
+        Logger {
+            instance_name: CString::new(instance_name.replace('\0', "")).unwrap().into(),
+            callbacks,
+            enabled: [logging_on; Category::ALL.len()],
+        }
+    }
+
+    pub fn instance_name(&self) -> &str {
+        self.instance_name.to_str().unwrap_or("")
+    }
+
+    /// Apply `fmi2SetDebugLogging`. Without categories `logging_on` applies to all of them,
+    /// otherwise only the given categories are switched. Unknown categories are returned as
+    /// an error and leave the logger unchanged.
+    pub fn set_debug_logging(&mut self, logging_on: bool, categories: &[&str]) -> Result<(), String> {
+        let mut enabled = self.enabled;
+        if categories.is_empty() {
+            enabled = [logging_on; Category::ALL.len()];
+        }
+        for name in categories {
+            if *name == "logAll" {
+                enabled = [logging_on; Category::ALL.len()];
+            } else {
+                let category = Category::from_name(name).ok_or_else(|| format!("Unknown log category {}", name))?;
+                enabled[category.index()] = logging_on;
+            }
+        }
+
+        self.enabled = enabled;
+        Ok(())
+    }
+
+    /// Errors are always reported, everything else only with debug logging on for its category.
+    pub fn is_enabled(&self, category: Category) -> bool {
+        match category {
+            Category::Error | Category::Fatal => true,
+            _ => self.enabled[category.index()],
+        }
+    }
+
+    pub fn log(&self, category: Category, message: &str) {
+        if !self.is_enabled(category) {
+            return;
+        }
+
+        let logger = match self.callbacks.and_then(|c| c.logger) {
+            Some(logger) => logger,
+            None => return,
+        };
+
+        let category_name = CString::new(category.name()).unwrap();
+        let message = CString::new(escape(message).replace('\0', "")).unwrap();
+        unsafe {
+            logger(
+                self.callbacks.unwrap().componentEnvironment,
+                self.instance_name.as_ptr(),
+                category.status(),
+                category_name.as_ptr(),
+                message.as_ptr(),
+            );
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT_LOGGER: Cell<Option<Logger>> = const { Cell::new(None) };
+}
+
+/// Log a message through the logger of the instance the current export is working on.
+pub fn log_current(category: Category, message: &str) {
+    // Taken out while logging, a callback that calls back into the FMU finds no current logger
+    let logger = CURRENT_LOGGER.with(|current| current.take());
+    if let Some(logger) = &logger {
+        logger.log(category, message);
+    }
+    CURRENT_LOGGER.with(|current| current.set(logger));
+}
+
+/// Makes a copy of a logger the current one for `log_current` until it is dropped.
+pub struct CurrentLogger {
+    previous: Option<Logger>,
+}
+
+impl CurrentLogger {
+    pub fn enter(logger: Option<&Logger>) -> CurrentLogger {
+        let previous = CURRENT_LOGGER.with(|current| current.replace(logger.cloned()));
+        CurrentLogger { previous }
+    }
+}
+
+impl Drop for CurrentLogger {
+    fn drop(&mut self) {
+        CURRENT_LOGGER.with(|current| current.set(self.previous.take()));
+    }
+}
+
+/// Read the `fmi2String` category names passed to `fmi2SetDebugLogging`.
+///
+/// # Safety
+///
+/// `categories` must be null or point to `n_categories` `fmi2String`s, each of them null or a
+/// nul-terminated string that outlives `'a`.
+pub unsafe fn category_names<'a>(categories: *const fmi2String, n_categories: usize) -> Vec<&'a str> {
+    if categories.is_null() || n_categories == 0 {
+        return Vec::new();
+    }
+
+    std::slice::from_raw_parts(categories, n_categories)
+        .iter()
+        .filter(|name| !name.is_null())
+        .map(|name| CStr::from_ptr(*name).to_str().unwrap_or(""))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("loggingOn = %d, 100%"), "loggingOn = %%d, 100%%");
+    }
+
+    #[test]
+    fn test_debug_logging_categories() {
+        let mut logger = Logger::new("instance", None, false);
+        assert!(!logger.is_enabled(Category::Events));
+        assert!(logger.is_enabled(Category::Error));
+
+        logger.set_debug_logging(true, &["logEvents"]).unwrap();
+        assert!(logger.is_enabled(Category::Events));
+        assert!(!logger.is_enabled(Category::Warning));
+
+        logger.set_debug_logging(true, &[]).unwrap();
+        assert!(logger.is_enabled(Category::Warning));
+
+        logger.set_debug_logging(false, &["logStatusWarning"]).unwrap();
+        assert!(logger.is_enabled(Category::Events));
+        assert!(!logger.is_enabled(Category::Warning));
+
+        assert!(logger.set_debug_logging(true, &["logNothing"]).is_err());
+
+        logger.set_debug_logging(false, &[]).unwrap();
+        assert!(!logger.is_enabled(Category::Events));
+    }
+}
@@ -0,0 +1,1788 @@
+//! The model description of an FMU, `modelDescription.xml`, as typed data.
+//!
+//! `ModelDescription` covers the FMI 2.0 schema: the attributes of the model, the capabilities
+//! of Model Exchange and Co-Simulation, units, type definitions, log categories, the default
+//! experiment, vendor annotations, the model variables and the model structure.
+//! `ModelDescription::to_xml` writes it and `ModelDescription::from_xml` reads it back, so
+//! neither the derive nor the packaging tools deal with XML themselves.
+//!
+//! `FmiModelStructDerive::model_variables` lists the scalar variables of a model, including the
+//! ones of flattened sub-structs. `ModelDescription::new` derives the definitions and the model
+//! structure from that list at runtime, since a model only learns about the variables of its
+//! children when they are put together.
+//!
+//! Attributes the crate has no use for, like the `quantity` of a variable, and the
+//! `<Annotations>` of variables are skipped when reading.
+
+use std::fmt;
+use std::str::FromStr;
+
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+
+use crate::units::{BaseUnit, Unit};
+use crate::{Causality, Initial, Variability};
+
+/// The type element of a scalar variable, with the attributes that depend on the type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VariableType {
+    Real {
+        /// The unit of the variable, with its definition.
+        unit: Option<UnitDefinition>,
+        /// The display unit the variable is shown in by default, one of the display units of
+        /// `unit`.
+        display_unit: Option<String>,
+        min: Option<f64>,
+        max: Option<f64>,
+        nominal: Option<f64>,
+        /// The value reference of the state this variable is the derivative of.
+        derivative: Option<u64>,
+    },
+    Integer {
+        min: Option<i32>,
+        max: Option<i32>,
+    },
+    Boolean,
+    String,
+    Enumeration {
+        /// The declared type, see `FmiEnumeration::type_definition`.
+        declared_type: SimpleType,
+    },
+}
+
+impl VariableType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VariableType::Real { .. } => "Real",
+            VariableType::Integer { .. } => "Integer",
+            VariableType::Boolean => "Boolean",
+            VariableType::String => "String",
+            VariableType::Enumeration { .. } => "Enumeration",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScalarVariable {
+    pub name: String,
+    pub value_reference: u64,
+    pub description: String,
+    pub causality: Causality,
+    pub variability: Variability,
+    pub initial: Option<Initial>,
+    pub variable_type: VariableType,
+    /// The start value as it is written to the model description.
+    pub start: Option<String>,
+    /// The variables an output or derivative depends on. `None` if they aren't declared, which
+    /// tells the master that it depends on all knowns. Only used to build the `ModelStructure`,
+    /// `ModelDescription::from_xml` leaves it `None`.
+    pub dependencies: Option<Vec<Dependency>>,
+}
+
+/// How an unknown depends on a known, the `dependenciesKind` of the FMI 2.0 standard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DependencyKind {
+    /// Any functional dependency, the default.
+    Dependent,
+    /// A linear dependency with a constant factor.
+    Constant,
+    /// A linear dependency with a factor that only changes during initialization.
+    Fixed,
+    /// A linear dependency with a factor that only changes at events through tunable parameters.
+    Tunable,
+    /// A linear dependency with a factor that only changes at events.
+    Discrete,
+}
+
+impl DependencyKind {
+    pub const ALL: [DependencyKind; 5] = [
+        DependencyKind::Dependent,
+        DependencyKind::Constant,
+        DependencyKind::Fixed,
+        DependencyKind::Tunable,
+        DependencyKind::Discrete,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DependencyKind::Dependent => "dependent",
+            DependencyKind::Constant => "constant",
+            DependencyKind::Fixed => "fixed",
+            DependencyKind::Tunable => "tunable",
+            DependencyKind::Discrete => "discrete",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<DependencyKind> {
+        DependencyKind::ALL.iter().copied().find(|k| k.name() == name)
+    }
+}
+
+/// A dependency on the variable `name`. The name of an array stands for all of its elements.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dependency {
+    pub name: String,
+    pub kind: DependencyKind,
+}
+
+/// How a value is written as the `start` attribute of a variable. Reals are written so that they
+/// read back as the same value, with `INF`, `-INF` and `NaN` as XML Schema spells them.
+pub trait StartValue {
+    fn start_value(&self) -> String;
+}
+
+macro_rules! real_start_value {
+    ($($ty:ty),*) => {
+        $(impl StartValue for $ty {
+            fn start_value(&self) -> String {
+                if self.is_nan() {
+                    "NaN".to_string()
+                } else if self.is_infinite() {
+                    if *self > 0.0 { "INF" } else { "-INF" }.to_string()
+                } else {
+                    // Debug is the shortest representation that round-trips
+                    format!("{:?}", self)
+                }
+            }
+        })*
+    };
+}
+
+macro_rules! display_start_value {
+    ($($ty:ty),*) => {
+        $(impl StartValue for $ty {
+            fn start_value(&self) -> String {
+                self.to_string()
+            }
+        })*
+    };
+}
+
+real_start_value!(f64, f32);
+display_start_value!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, bool, String);
+
+/// A `<Unit>` of `<UnitDefinitions>`. Models declare their units as `crate::units::Unit`, this
+/// is the owned form a model description is made of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnitDefinition {
+    pub name: String,
+    pub base_unit: Option<BaseUnit>,
+    pub display_units: Vec<DisplayUnitDefinition>,
+}
+
+/// A `<DisplayUnit>` of a unit: `value_in_display_unit = factor * value + offset`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisplayUnitDefinition {
+    pub name: String,
+    pub factor: f64,
+    pub offset: f64,
+}
+
+impl UnitDefinition {
+    /// A unit that is only known by its name.
+    pub fn named(name: &str) -> UnitDefinition {
+        UnitDefinition {
+            name: name.to_string(),
+            base_unit: None,
+            display_units: Vec::new(),
+        }
+    }
+}
+
+impl From<Unit> for UnitDefinition {
+    fn from(unit: Unit) -> Self {
+        UnitDefinition {
+            name: unit.name.to_string(),
+            base_unit: unit.base_unit,
+            display_units: unit
+                .display_units
+                .iter()
+                .map(|d| DisplayUnitDefinition {
+                    name: d.name.to_string(),
+                    factor: d.factor,
+                    offset: d.offset,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A `<SimpleType>` of `<TypeDefinitions>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimpleType {
+    pub name: String,
+    pub description: String,
+    pub kind: SimpleTypeKind,
+}
+
+/// The type element of a `<SimpleType>`, with the attributes that depend on the type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimpleTypeKind {
+    Real {
+        quantity: Option<String>,
+        unit: Option<String>,
+        display_unit: Option<String>,
+        min: Option<f64>,
+        max: Option<f64>,
+        nominal: Option<f64>,
+    },
+    Integer {
+        quantity: Option<String>,
+        min: Option<i32>,
+        max: Option<i32>,
+    },
+    Boolean,
+    String,
+    Enumeration {
+        quantity: Option<String>,
+        items: Vec<Item>,
+    },
+}
+
+/// An `<Item>` of an Enumeration type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub name: String,
+    pub value: i32,
+    pub description: String,
+}
+
+/// How the names of the variables are built, `flat` allows any name, `structured` uses `.`
+/// and `[]` for the elements of structs and arrays.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VariableNamingConvention {
+    #[default]
+    Flat,
+    Structured,
+}
+
+impl VariableNamingConvention {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VariableNamingConvention::Flat => "flat",
+            VariableNamingConvention::Structured => "structured",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<VariableNamingConvention> {
+        [VariableNamingConvention::Flat, VariableNamingConvention::Structured]
+            .into_iter()
+            .find(|c| c.name() == name)
+    }
+}
+
+/// The capabilities of the Model Exchange interface, `<ModelExchange>`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelExchange {
+    pub model_identifier: String,
+    pub needs_execution_tool: bool,
+    pub completed_integrator_step_not_needed: bool,
+    pub can_be_instantiated_only_once_per_process: bool,
+    pub can_not_use_memory_management_functions: bool,
+    pub can_get_and_set_fmu_state: bool,
+    pub can_serialize_fmu_state: bool,
+    pub provides_directional_derivative: bool,
+    pub source_files: Vec<String>,
+}
+
+/// The capabilities of the Co-Simulation interface, `<CoSimulation>`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CoSimulation {
+    pub model_identifier: String,
+    pub needs_execution_tool: bool,
+    pub can_handle_variable_communication_step_size: bool,
+    pub can_interpolate_inputs: bool,
+    pub max_output_derivative_order: u32,
+    pub can_run_asynchronuously: bool,
+    pub can_be_instantiated_only_once_per_process: bool,
+    pub can_not_use_memory_management_functions: bool,
+    pub can_get_and_set_fmu_state: bool,
+    pub can_serialize_fmu_state: bool,
+    pub provides_directional_derivative: bool,
+    pub source_files: Vec<String>,
+}
+
+/// A `<Category>` of `<LogCategories>`, see `crate::logger::Category`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogCategory {
+    pub name: String,
+    pub description: String,
+}
+
+/// The `<DefaultExperiment>`, the settings a simulation of the model should use if nothing else
+/// is given.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DefaultExperiment {
+    pub start_time: Option<f64>,
+    pub stop_time: Option<f64>,
+    pub tolerance: Option<f64>,
+    pub step_size: Option<f64>,
+}
+
+/// A `<Tool>` of `<VendorAnnotations>`. The XML inside it is kept as it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tool {
+    pub name: String,
+    pub content: String,
+}
+
+/// An `<Unknown>` of `<ModelStructure>`: the index of a variable, counting from 1, and the
+/// indices of the knowns it depends on, `None` if they aren't declared.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unknown {
+    pub index: usize,
+    pub dependencies: Option<Vec<(usize, DependencyKind)>>,
+}
+
+/// The `<ModelStructure>`: the outputs, the derivatives of the continuous states and the
+/// unknowns of the initialization.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelStructure {
+    pub outputs: Vec<Unknown>,
+    pub derivatives: Vec<Unknown>,
+    pub initial_unknowns: Vec<Unknown>,
+}
+
+impl ModelStructure {
+    /// The model structure of `variables`, with the declared dependencies resolved to indices.
+    ///
+    /// Fails if a dependency names a variable that doesn't exist, or if an output or a
+    /// derivative depends on something else than an input, a continuous state or the independent
+    /// variable.
+    pub fn from_variables(variables: &[ScalarVariable]) -> Result<ModelStructure, ModelError> {
+        let states: Vec<u64> = variables
+            .iter()
+            .filter_map(|v| match v.variable_type {
+                VariableType::Real { derivative, .. } => derivative,
+                _ => None,
+            })
+            .collect();
+        let is_derivative = |v: &ScalarVariable| matches!(v.variable_type, VariableType::Real { derivative: Some(_), .. });
+        let is_calculated = |v: &ScalarVariable| matches!(v.initial, Some(Initial::Approx | Initial::Calculated));
+
+        let outputs = unknowns(variables, |v| v.causality == Causality::Output)?;
+        let derivatives = unknowns(variables, is_derivative)?;
+        let is_known = |v: &ScalarVariable| {
+            matches!(v.causality, Causality::Input | Causality::Independent) || states.contains(&v.value_reference)
+        };
+        for unknown in outputs.iter().chain(&derivatives) {
+            for (index, _) in unknown.dependencies.iter().flatten() {
+                if !is_known(&variables[index - 1]) {
+                    return Err(ModelError::InvalidDependency {
+                        variable: variables[unknown.index - 1].name.clone(),
+                        dependency: variables[index - 1].name.clone(),
+                    });
+                }
+            }
+        }
+
+        let initial_unknowns = unknowns(variables, |v| {
+            v.causality == Causality::CalculatedParameter
+                || (is_calculated(v)
+                    && (v.causality == Causality::Output || is_derivative(v) || states.contains(&v.value_reference)))
+        })?;
+
+        // During initialization only dependent and constant are allowed, and the dependencies
+        // have to be knowns of the initialization: inputs, the independent variable, exact
+        // parameters and the states with an exact start value. If one of them isn't, leave them
+        // out, which means a dependency on all knowns.
+        let unknown_indices: Vec<usize> = initial_unknowns.iter().map(|unknown| unknown.index).collect();
+        let is_initial_known = |index: &usize| {
+            let v = &variables[index - 1];
+            let is_exact = matches!(v.initial, None | Some(Initial::Exact));
+            !unknown_indices.contains(index)
+                && (is_known(v) || (v.causality == Causality::Parameter && is_exact))
+        };
+        let initial_unknowns = initial_unknowns
+            .into_iter()
+            .map(|unknown| Unknown {
+                index: unknown.index,
+                dependencies: unknown
+                    .dependencies
+                    .filter(|d| d.iter().all(|(index, _)| is_initial_known(index)))
+                    .map(|d| {
+                        d.into_iter()
+                            .map(|(index, kind)| match kind {
+                                DependencyKind::Constant => (index, kind),
+                                _ => (index, DependencyKind::Dependent),
+                            })
+                            .collect()
+                    }),
+            })
+            .collect();
+
+        Ok(ModelStructure {
+            outputs,
+            derivatives,
+            initial_unknowns,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.outputs.is_empty() && self.derivatives.is_empty() && self.initial_unknowns.is_empty()
+    }
+}
+
+/// The indices of the variables that match `filter`, with their dependencies resolved to indices.
+fn unknowns(variables: &[ScalarVariable], filter: impl Fn(&ScalarVariable) -> bool) -> Result<Vec<Unknown>, ModelError> {
+    variables
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| filter(v))
+        .map(|(index, variable)| {
+            let dependencies = match &variable.dependencies {
+                Some(dependencies) => {
+                    let mut indices: Vec<(usize, DependencyKind)> = Vec::new();
+                    for dependency in dependencies {
+                        let element_prefix = format!("{}[", dependency.name);
+                        let matching = variables
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, v)| v.name == dependency.name || v.name.starts_with(&element_prefix))
+                            .map(|(index, _)| (index + 1, dependency.kind));
+                        let count = indices.len();
+                        indices.extend(matching);
+                        if indices.len() == count {
+                            return Err(ModelError::UnknownDependency {
+                                variable: variable.name.clone(),
+                                dependency: dependency.name.clone(),
+                            });
+                        }
+                    }
+                    indices.sort_by_key(|(index, _)| *index);
+                    indices.dedup_by_key(|(index, _)| *index);
+                    Some(indices)
+                }
+                None => None,
+            };
+            Ok(Unknown {
+                index: index + 1,
+                dependencies,
+            })
+        })
+        .collect()
+}
+
+/// A `modelDescription.xml` of FMI 2.0.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelDescription {
+    pub model_name: String,
+    pub guid: String,
+    pub description: String,
+    pub author: Option<String>,
+    pub version: Option<String>,
+    pub copyright: Option<String>,
+    pub license: Option<String>,
+    pub generation_tool: Option<String>,
+    pub generation_date_and_time: Option<String>,
+    pub variable_naming_convention: VariableNamingConvention,
+    pub number_of_event_indicators: usize,
+    pub model_exchange: Option<ModelExchange>,
+    pub co_simulation: Option<CoSimulation>,
+    pub unit_definitions: Vec<UnitDefinition>,
+    pub type_definitions: Vec<SimpleType>,
+    pub log_categories: Vec<LogCategory>,
+    pub default_experiment: Option<DefaultExperiment>,
+    pub vendor_annotations: Vec<Tool>,
+    pub model_variables: Vec<ScalarVariable>,
+    pub model_structure: ModelStructure,
+}
+
+impl ModelDescription {
+    /// The model description of a model with the given variables, with the units and types they
+    /// use sorted into the definitions and the model structure derived from them. Everything
+    /// else is left at the defaults.
+    ///
+    /// Fails if two variables have the same value reference, which the derive can only rule out
+    /// for the variables of one struct, not for the ones of flattened children. Fails as well if
+    /// a variable has a display unit its unit doesn't declare, if two variables use different
+    /// definitions of a unit, or if the dependencies are invalid, see
+    /// `ModelStructure::from_variables`.
+    pub fn new(model_name: &str, guid: &str, model_variables: Vec<ScalarVariable>) -> Result<ModelDescription, ModelError> {
+        for (index, variable) in model_variables.iter().enumerate() {
+            if let Some(other) = model_variables[..index].iter().find(|v| v.value_reference == variable.value_reference) {
+                return Err(ModelError::ValueReferenceCollision {
+                    first: other.name.clone(),
+                    second: variable.name.clone(),
+                    value_reference: variable.value_reference,
+                });
+            }
+            if let VariableType::Real { unit, display_unit: Some(display_unit), .. } = &variable.variable_type {
+                let declared = unit.as_ref().is_some_and(|u| u.display_units.iter().any(|d| d.name == *display_unit));
+                if !declared {
+                    return Err(ModelError::UndeclaredDisplayUnit {
+                        variable: variable.name.clone(),
+                        display_unit: display_unit.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(ModelDescription {
+            model_name: model_name.to_string(),
+            guid: guid.to_string(),
+            unit_definitions: unit_definitions(&model_variables)?,
+            type_definitions: type_definitions(&model_variables),
+            model_structure: ModelStructure::from_variables(&model_variables)?,
+            model_variables,
+            ..ModelDescription::default()
+        })
+    }
+
+    /// The model description as the content of `modelDescription.xml`, encoded as UTF-8.
+    pub fn to_xml(&self) -> String {
+        let mut writer = XmlWriter(Writer::new_with_indent(Vec::new(), b' ', 4));
+        writer.event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)));
+
+        writer.start(element(
+            "fmiModelDescription",
+            &[
+                ("fmiVersion", Some("2.0".to_string())),
+                ("modelName", Some(self.model_name.clone())),
+                ("guid", Some(self.guid.clone())),
+                ("description", non_empty(&self.description)),
+                ("author", self.author.clone()),
+                ("version", self.version.clone()),
+                ("copyright", self.copyright.clone()),
+                ("license", self.license.clone()),
+                ("generationTool", self.generation_tool.clone()),
+                ("generationDateAndTime", self.generation_date_and_time.clone()),
+                ("variableNamingConvention", Some(self.variable_naming_convention.name().to_string())),
+                ("numberOfEventIndicators", Some(self.number_of_event_indicators.to_string())),
+            ],
+        ));
+
+        if let Some(me) = &self.model_exchange {
+            writer.capabilities(
+                "ModelExchange",
+                &[
+                    ("modelIdentifier", Some(me.model_identifier.clone())),
+                    ("needsExecutionTool", flag(me.needs_execution_tool)),
+                    ("completedIntegratorStepNotNeeded", flag(me.completed_integrator_step_not_needed)),
+                    ("canBeInstantiatedOnlyOncePerProcess", flag(me.can_be_instantiated_only_once_per_process)),
+                    ("canNotUseMemoryManagementFunctions", flag(me.can_not_use_memory_management_functions)),
+                    ("canGetAndSetFMUstate", flag(me.can_get_and_set_fmu_state)),
+                    ("canSerializeFMUstate", flag(me.can_serialize_fmu_state)),
+                    ("providesDirectionalDerivative", flag(me.provides_directional_derivative)),
+                ],
+                &me.source_files,
+            );
+        }
+        if let Some(cs) = &self.co_simulation {
+            writer.capabilities(
+                "CoSimulation",
+                &[
+                    ("modelIdentifier", Some(cs.model_identifier.clone())),
+                    ("needsExecutionTool", flag(cs.needs_execution_tool)),
+                    ("canHandleVariableCommunicationStepSize", flag(cs.can_handle_variable_communication_step_size)),
+                    ("canInterpolateInputs", flag(cs.can_interpolate_inputs)),
+                    ("maxOutputDerivativeOrder", Some(cs.max_output_derivative_order.to_string())),
+                    ("canRunAsynchronuously", flag(cs.can_run_asynchronuously)),
+                    ("canBeInstantiatedOnlyOncePerProcess", flag(cs.can_be_instantiated_only_once_per_process)),
+                    ("canNotUseMemoryManagementFunctions", flag(cs.can_not_use_memory_management_functions)),
+                    ("canGetAndSetFMUstate", flag(cs.can_get_and_set_fmu_state)),
+                    ("canSerializeFMUstate", flag(cs.can_serialize_fmu_state)),
+                    ("providesDirectionalDerivative", flag(cs.provides_directional_derivative)),
+                ],
+                &cs.source_files,
+            );
+        }
+
+        writer.list("UnitDefinitions", &self.unit_definitions, |writer, unit| {
+            let unit_element = element("Unit", &[("name", Some(unit.name.clone()))]);
+            if unit.base_unit.is_none() && unit.display_units.is_empty() {
+                writer.empty(unit_element);
+                return;
+            }
+
+            writer.start(unit_element);
+            if let Some(base_unit) = &unit.base_unit {
+                let exponents = [
+                    ("kg", base_unit.kg),
+                    ("m", base_unit.m),
+                    ("s", base_unit.s),
+                    ("A", base_unit.a),
+                    ("K", base_unit.k),
+                    ("mol", base_unit.mol),
+                    ("cd", base_unit.cd),
+                    ("rad", base_unit.rad),
+                ];
+                let mut attributes: Vec<(&str, Option<String>)> = exponents
+                    .iter()
+                    .map(|(name, exponent)| (*name, (*exponent != 0).then(|| exponent.to_string())))
+                    .collect();
+                attributes.extend(conversion_attributes(base_unit.factor, base_unit.offset));
+                writer.empty(element("BaseUnit", &attributes));
+            }
+            for display_unit in &unit.display_units {
+                let mut attributes = vec![("name", Some(display_unit.name.clone()))];
+                attributes.extend(conversion_attributes(display_unit.factor, display_unit.offset));
+                writer.empty(element("DisplayUnit", &attributes));
+            }
+            writer.end("Unit");
+        });
+
+        writer.list("TypeDefinitions", &self.type_definitions, |writer, simple_type| {
+            writer.start(element(
+                "SimpleType",
+                &[("name", Some(simple_type.name.clone())), ("description", non_empty(&simple_type.description))],
+            ));
+            match &simple_type.kind {
+                SimpleTypeKind::Real { quantity, unit, display_unit, min, max, nominal } => writer.empty(element(
+                    "Real",
+                    &[
+                        ("quantity", quantity.clone()),
+                        ("unit", unit.clone()),
+                        ("displayUnit", display_unit.clone()),
+                        ("min", min.map(real)),
+                        ("max", max.map(real)),
+                        ("nominal", nominal.map(real)),
+                    ],
+                )),
+                SimpleTypeKind::Integer { quantity, min, max } => writer.empty(element(
+                    "Integer",
+                    &[("quantity", quantity.clone()), ("min", min.map(|v| v.to_string())), ("max", max.map(|v| v.to_string()))],
+                )),
+                SimpleTypeKind::Boolean => writer.empty(BytesStart::new("Boolean")),
+                SimpleTypeKind::String => writer.empty(BytesStart::new("String")),
+                SimpleTypeKind::Enumeration { quantity, items } => {
+                    writer.start(element("Enumeration", &[("quantity", quantity.clone())]));
+                    for item in items {
+                        writer.empty(element(
+                            "Item",
+                            &[
+                                ("name", Some(item.name.clone())),
+                                ("value", Some(item.value.to_string())),
+                                ("description", non_empty(&item.description)),
+                            ],
+                        ));
+                    }
+                    writer.end("Enumeration");
+                }
+            }
+            writer.end("SimpleType");
+        });
+
+        writer.list("LogCategories", &self.log_categories, |writer, category| {
+            writer.empty(element(
+                "Category",
+                &[("name", Some(category.name.clone())), ("description", non_empty(&category.description))],
+            ));
+        });
+
+        if let Some(experiment) = &self.default_experiment {
+            writer.empty(element(
+                "DefaultExperiment",
+                &[
+                    ("startTime", experiment.start_time.map(real)),
+                    ("stopTime", experiment.stop_time.map(real)),
+                    ("tolerance", experiment.tolerance.map(real)),
+                    ("stepSize", experiment.step_size.map(real)),
+                ],
+            ));
+        }
+
+        writer.list("VendorAnnotations", &self.vendor_annotations, |writer, tool| {
+            writer.start(element("Tool", &[("name", Some(tool.name.clone()))]));
+            writer.event(Event::Text(BytesText::from_escaped(tool.content.as_str())));
+            writer.end("Tool");
+        });
+
+        if self.model_variables.is_empty() {
+            writer.empty(BytesStart::new("ModelVariables"));
+        }
+        writer.list("ModelVariables", &self.model_variables, |writer, variable| {
+            writer.start(element(
+                "ScalarVariable",
+                &[
+                    ("name", Some(variable.name.clone())),
+                    ("valueReference", Some(variable.value_reference.to_string())),
+                    ("description", non_empty(&variable.description)),
+                    ("causality", Some(variable.causality.name().to_string())),
+                    ("variability", Some(variable.variability.name().to_string())),
+                    ("initial", variable.initial.map(|initial| initial.name().to_string())),
+                ],
+            ));
+
+            let start = ("start", variable.start.clone());
+            writer.empty(match &variable.variable_type {
+                VariableType::Real { unit, display_unit, min, max, nominal, derivative } => element(
+                    "Real",
+                    &[
+                        ("unit", unit.as_ref().map(|unit| unit.name.clone())),
+                        ("displayUnit", display_unit.clone()),
+                        ("min", min.map(real)),
+                        ("max", max.map(real)),
+                        ("nominal", nominal.map(real)),
+                        ("derivative", derivative.and_then(|vr| index_of(&self.model_variables, vr)).map(|i| i.to_string())),
+                        start,
+                    ],
+                ),
+                VariableType::Integer { min, max } => {
+                    element("Integer", &[("min", min.map(|v| v.to_string())), ("max", max.map(|v| v.to_string())), start])
+                }
+                VariableType::Boolean => element("Boolean", &[start]),
+                VariableType::String => element("String", &[start]),
+                VariableType::Enumeration { declared_type } => {
+                    element("Enumeration", &[("declaredType", Some(declared_type.name.clone())), start])
+                }
+            });
+            writer.end("ScalarVariable");
+        });
+
+        let structure = &self.model_structure;
+        if structure.is_empty() {
+            writer.empty(BytesStart::new("ModelStructure"));
+        } else {
+            writer.start(BytesStart::new("ModelStructure"));
+            for (name, unknowns) in [
+                ("Outputs", &structure.outputs),
+                ("Derivatives", &structure.derivatives),
+                ("InitialUnknowns", &structure.initial_unknowns),
+            ] {
+                writer.list(name, unknowns, |writer, unknown| {
+                    writer.empty(element("Unknown", &unknown_attributes(unknown)));
+                });
+            }
+            writer.end("ModelStructure");
+        }
+
+        writer.end("fmiModelDescription");
+        let mut xml = String::from_utf8(writer.0.into_inner()).expect("the writer only writes UTF-8");
+        xml.push('\n');
+        xml
+    }
+
+    /// Read a model description from the content of `modelDescription.xml`.
+    pub fn from_xml(xml: &str) -> Result<ModelDescription, ParseError> {
+        let mut reader = Reader::from_str(xml);
+        reader.trim_text(true);
+        loop {
+            match reader.read_event()? {
+                Event::Start(element) => return read_model_description(&mut reader, &element, false),
+                Event::Empty(element) => return read_model_description(&mut reader, &element, true),
+                Event::Eof => return Err(ParseError::Xml("the document has no fmiModelDescription".to_string())),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The units of the Real variables sorted by name.
+///
+/// Fails if two variables use different definitions of a unit.
+fn unit_definitions(variables: &[ScalarVariable]) -> Result<Vec<UnitDefinition>, ModelError> {
+    let mut units: Vec<UnitDefinition> = Vec::new();
+    for variable in variables {
+        if let VariableType::Real { unit: Some(unit), .. } = &variable.variable_type {
+            match units.iter().find(|u| u.name == unit.name) {
+                Some(u) if u != unit => {
+                    return Err(ModelError::ConflictingUnit {
+                        variable: variable.name.clone(),
+                        unit: unit.name.clone(),
+                    })
+                }
+                Some(_) => (),
+                None => units.push(unit.clone()),
+            }
+        }
+    }
+    units.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(units)
+}
+
+/// The declared types of the Enumeration variables, in the order they are first used.
+fn type_definitions(variables: &[ScalarVariable]) -> Vec<SimpleType> {
+    let mut types: Vec<SimpleType> = Vec::new();
+    for variable in variables {
+        if let VariableType::Enumeration { declared_type } = &variable.variable_type {
+            if !types.iter().any(|t| t.name == declared_type.name) {
+                types.push(declared_type.clone());
+            }
+        }
+    }
+    types
+}
+
+/// A `quick_xml::Writer` into memory, which can't fail.
+struct XmlWriter(Writer<Vec<u8>>);
+
+impl XmlWriter {
+    fn event(&mut self, event: Event) {
+        self.0.write_event(event).expect("writing to memory can't fail");
+    }
+
+    fn start(&mut self, element: BytesStart) {
+        self.event(Event::Start(element));
+    }
+
+    fn empty(&mut self, element: BytesStart) {
+        self.event(Event::Empty(element));
+    }
+
+    fn end(&mut self, name: &str) {
+        self.event(Event::End(BytesEnd::new(name)));
+    }
+
+    /// The element `name` with an element for each item, left out if there are no items.
+    fn list<T>(&mut self, name: &str, items: &[T], mut write: impl FnMut(&mut XmlWriter, &T)) {
+        if items.is_empty() {
+            return;
+        }
+        self.start(BytesStart::new(name));
+        for item in items {
+            write(self, item);
+        }
+        self.end(name);
+    }
+
+    /// `<ModelExchange>` or `<CoSimulation>`, with the source files if there are any.
+    fn capabilities(&mut self, name: &str, attributes: &[(&str, Option<String>)], source_files: &[String]) {
+        if source_files.is_empty() {
+            self.empty(element(name, attributes));
+            return;
+        }
+        self.start(element(name, attributes));
+        self.list("SourceFiles", source_files, |writer, file| {
+            writer.empty(element("File", &[("name", Some(file.clone()))]));
+        });
+        self.end(name);
+    }
+}
+
+/// An element with the given attributes, leaving out the ones that are `None`.
+fn element<'a>(name: &'a str, attributes: &[(&str, Option<String>)]) -> BytesStart<'a> {
+    let mut element = BytesStart::new(name);
+    for (key, value) in attributes {
+        if let Some(value) = value {
+            element.push_attribute((*key, value.as_str()));
+        }
+    }
+    element
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// A Real attribute, written like the start values so that all of them read back the same.
+fn real(value: f64) -> String {
+    value.start_value()
+}
+
+fn flag(value: bool) -> Option<String> {
+    Some(value.to_string())
+}
+
+/// The `factor` and `offset` attributes, left out if they are the defaults.
+fn conversion_attributes(factor: f64, offset: f64) -> [(&'static str, Option<String>); 2] {
+    [
+        ("factor", (factor != 1.0).then(|| real(factor))),
+        ("offset", (offset != 0.0).then(|| real(offset))),
+    ]
+}
+
+/// The attributes of an `<Unknown>`. `dependenciesKind` is left out if all dependencies are
+/// `dependent`.
+fn unknown_attributes(unknown: &Unknown) -> [(&'static str, Option<String>); 3] {
+    let join = |parts: Vec<String>| Some(parts.join(" "));
+    let (dependencies, kinds) = match &unknown.dependencies {
+        Some(dependencies) => (
+            join(dependencies.iter().map(|(index, _)| index.to_string()).collect()),
+            if dependencies.iter().any(|(_, kind)| *kind != DependencyKind::Dependent) {
+                join(dependencies.iter().map(|(_, kind)| kind.name().to_string()).collect())
+            } else {
+                None
+            },
+        ),
+        None => (None, None),
+    };
+    [
+        ("index", Some(unknown.index.to_string())),
+        ("dependencies", dependencies),
+        ("dependenciesKind", kinds),
+    ]
+}
+
+/// Why `ModelDescription::new` couldn't put together the model description of a model from its
+/// variables.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModelError {
+    /// Two variables have the same value reference.
+    ValueReferenceCollision { first: String, second: String, value_reference: u64 },
+    /// A variable has a display unit that its unit doesn't declare.
+    UndeclaredDisplayUnit { variable: String, display_unit: String },
+    /// A variable uses a different definition of a unit than one before it.
+    ConflictingUnit { variable: String, unit: String },
+    /// A dependency names a variable that doesn't exist.
+    UnknownDependency { variable: String, dependency: String },
+    /// An output or a derivative depends on something else than an input, a continuous state or
+    /// the independent variable.
+    InvalidDependency { variable: String, dependency: String },
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModelError::ValueReferenceCollision { first, second, value_reference } => {
+                write!(f, "{} and {} have the same value reference {}", first, second, value_reference)
+            }
+            ModelError::UndeclaredDisplayUnit { variable, display_unit } => {
+                write!(f, "{}: {} is not a display unit of its unit", variable, display_unit)
+            }
+            ModelError::ConflictingUnit { variable, unit } => {
+                write!(f, "{}: the unit {} has two different definitions", variable, unit)
+            }
+            ModelError::UnknownDependency { variable, dependency } => {
+                write!(f, "{}: depends on {}, which is not a variable of the model", variable, dependency)
+            }
+            ModelError::InvalidDependency { variable, dependency } => write!(
+                f,
+                "{}: depends on {}, which is not an input, a continuous state or the independent variable",
+                variable, dependency
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+/// Why `ModelDescription::from_xml` couldn't read a model description.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// The document isn't well-formed XML or has no `<fmiModelDescription>`.
+    Xml(String),
+    /// The model description is for a different version of FMI.
+    UnsupportedVersion(String),
+    /// An element lacks an attribute the schema requires.
+    MissingAttribute { element: String, attribute: String },
+    /// An attribute doesn't have a value of the type the schema gives it, or refers to something
+    /// that isn't defined.
+    InvalidAttribute { element: String, attribute: String, value: String },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Xml(e) => write!(f, "invalid model description: {}", e),
+            ParseError::UnsupportedVersion(version) => {
+                write!(f, "unsupported model description for FMI {} (expected 2.0)", version)
+            }
+            ParseError::MissingAttribute { element, attribute } => {
+                write!(f, "<{}> has no {} attribute", element, attribute)
+            }
+            ParseError::InvalidAttribute { element, attribute, value } => {
+                write!(f, "<{}> has an invalid {} attribute \"{}\"", element, attribute, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<quick_xml::Error> for ParseError {
+    fn from(e: quick_xml::Error) -> Self {
+        ParseError::Xml(e.to_string())
+    }
+}
+
+type XmlReader<'a> = Reader<&'a [u8]>;
+
+/// The attributes of an element, unescaped.
+struct Attributes {
+    element: String,
+    values: Vec<(String, String)>,
+}
+
+impl Attributes {
+    fn of(element: &BytesStart) -> Result<Attributes, ParseError> {
+        let mut values = Vec::new();
+        for attribute in element.attributes() {
+            let attribute = attribute.map_err(|e| ParseError::Xml(e.to_string()))?;
+            let key = String::from_utf8_lossy(attribute.key.as_ref()).into_owned();
+            values.push((key, attribute.unescape_value()?.into_owned()));
+        }
+        Ok(Attributes {
+            element: String::from_utf8_lossy(element.name().as_ref()).into_owned(),
+            values,
+        })
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.values.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone())
+    }
+
+    fn required(&self, name: &str) -> Result<String, ParseError> {
+        self.get(name).ok_or_else(|| ParseError::MissingAttribute {
+            element: self.element.clone(),
+            attribute: name.to_string(),
+        })
+    }
+
+    /// The attribute read with `parse`, `None` if it isn't there.
+    fn parse_with<T>(&self, name: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Option<T>, ParseError> {
+        match self.get(name) {
+            Some(value) => parse(value.trim()).map(Some).ok_or_else(|| self.invalid(name, &value)),
+            None => Ok(None),
+        }
+    }
+
+    fn parse<T: FromStr>(&self, name: &str) -> Result<Option<T>, ParseError> {
+        self.parse_with(name, |value| value.parse().ok())
+    }
+
+    fn parse_required<T: FromStr>(&self, name: &str) -> Result<T, ParseError> {
+        self.required(name)?;
+        Ok(self.parse(name)?.unwrap())
+    }
+
+    /// A boolean attribute, `false` if it isn't there.
+    fn flag(&self, name: &str) -> Result<bool, ParseError> {
+        let parse = |value: &str| match value {
+            "true" | "1" => Some(true),
+            "false" | "0" => Some(false),
+            _ => None,
+        };
+        Ok(self.parse_with(name, parse)?.unwrap_or(false))
+    }
+
+    fn invalid(&self, name: &str, value: &str) -> ParseError {
+        ParseError::InvalidAttribute {
+            element: self.element.clone(),
+            attribute: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+/// Reads the content of the element that was just read, passing each child element to `child`
+/// together with whether it is empty. `child` has to read the content of the ones that aren't,
+/// e.g. with `skip`.
+fn children<'a>(
+    reader: &mut XmlReader<'a>,
+    empty: bool,
+    mut child: impl FnMut(&mut XmlReader<'a>, &BytesStart<'a>, bool) -> Result<(), ParseError>,
+) -> Result<(), ParseError> {
+    if empty {
+        return Ok(());
+    }
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => child(reader, &element, false)?,
+            Event::Empty(element) => child(reader, &element, true)?,
+            Event::End(_) => return Ok(()),
+            Event::Eof => return Err(ParseError::Xml("unexpected end of the document".to_string())),
+            _ => {}
+        }
+    }
+}
+
+/// Reads the content of a list element, with `read` for the children called `name`. Other
+/// children are skipped.
+fn read_list<'a, T>(
+    reader: &mut XmlReader<'a>,
+    empty: bool,
+    name: &str,
+    mut read: impl FnMut(&mut XmlReader<'a>, &BytesStart<'a>, bool) -> Result<T, ParseError>,
+) -> Result<Vec<T>, ParseError> {
+    let mut items = Vec::new();
+    children(reader, empty, |reader, element, empty| {
+        if element.name().as_ref() == name.as_bytes() {
+            items.push(read(reader, element, empty)?);
+            Ok(())
+        } else {
+            skip(reader, element, empty)
+        }
+    })?;
+    Ok(items)
+}
+
+/// Skips the content of an element.
+fn skip(reader: &mut XmlReader, element: &BytesStart, empty: bool) -> Result<(), ParseError> {
+    if !empty {
+        reader.read_to_end(element.name())?;
+    }
+    Ok(())
+}
+
+fn read_model_description<'a>(reader: &mut XmlReader<'a>, element: &BytesStart<'a>, empty: bool) -> Result<ModelDescription, ParseError> {
+    let attributes = Attributes::of(element)?;
+    if attributes.element != "fmiModelDescription" {
+        return Err(ParseError::Xml(format!("expected fmiModelDescription, found {}", attributes.element)));
+    }
+    let fmi_version = attributes.required("fmiVersion")?;
+    if fmi_version.trim() != "2.0" {
+        return Err(ParseError::UnsupportedVersion(fmi_version));
+    }
+
+    let mut model = ModelDescription {
+        model_name: attributes.required("modelName")?,
+        guid: attributes.required("guid")?,
+        description: attributes.get("description").unwrap_or_default(),
+        author: attributes.get("author"),
+        version: attributes.get("version"),
+        copyright: attributes.get("copyright"),
+        license: attributes.get("license"),
+        generation_tool: attributes.get("generationTool"),
+        generation_date_and_time: attributes.get("generationDateAndTime"),
+        variable_naming_convention: attributes
+            .parse_with("variableNamingConvention", VariableNamingConvention::from_name)?
+            .unwrap_or_default(),
+        number_of_event_indicators: attributes.parse("numberOfEventIndicators")?.unwrap_or(0),
+        ..ModelDescription::default()
+    };
+
+    children(reader, empty, |reader, element, empty| {
+        match element.name().as_ref() {
+            b"ModelExchange" => model.model_exchange = Some(read_model_exchange(reader, element, empty)?),
+            b"CoSimulation" => model.co_simulation = Some(read_co_simulation(reader, element, empty)?),
+            b"UnitDefinitions" => model.unit_definitions = read_list(reader, empty, "Unit", read_unit)?,
+            b"TypeDefinitions" => model.type_definitions = read_list(reader, empty, "SimpleType", read_simple_type)?,
+            b"LogCategories" => {
+                model.log_categories = read_list(reader, empty, "Category", |reader, element, empty| {
+                    let attributes = Attributes::of(element)?;
+                    skip(reader, element, empty)?;
+                    Ok(LogCategory {
+                        name: attributes.required("name")?,
+                        description: attributes.get("description").unwrap_or_default(),
+                    })
+                })?
+            }
+            b"DefaultExperiment" => {
+                let attributes = Attributes::of(element)?;
+                skip(reader, element, empty)?;
+                model.default_experiment = Some(DefaultExperiment {
+                    start_time: attributes.parse("startTime")?,
+                    stop_time: attributes.parse("stopTime")?,
+                    tolerance: attributes.parse("tolerance")?,
+                    step_size: attributes.parse("stepSize")?,
+                });
+            }
+            b"VendorAnnotations" => {
+                model.vendor_annotations = read_list(reader, empty, "Tool", |reader, element, empty| {
+                    let name = Attributes::of(element)?.required("name")?;
+                    let content = if empty { String::new() } else { reader.read_text(element.name())?.into_owned() };
+                    Ok(Tool { name, content })
+                })?
+            }
+            b"ModelVariables" => {
+                let (units, types) = (&model.unit_definitions, &model.type_definitions);
+                model.model_variables = read_list(reader, empty, "ScalarVariable", |reader, element, empty| {
+                    read_scalar_variable(reader, element, empty, units, types)
+                })?;
+            }
+            b"ModelStructure" => {
+                children(reader, empty, |reader, element, empty| {
+                    let unknowns = match element.name().as_ref() {
+                        b"Outputs" => &mut model.model_structure.outputs,
+                        b"Derivatives" => &mut model.model_structure.derivatives,
+                        b"InitialUnknowns" => &mut model.model_structure.initial_unknowns,
+                        _ => return skip(reader, element, empty),
+                    };
+                    *unknowns = read_list(reader, empty, "Unknown", read_unknown)?;
+                    Ok(())
+                })?;
+            }
+            _ => skip(reader, element, empty)?,
+        }
+        Ok(())
+    })?;
+
+    // The derivatives were read as the indices of their states, which are only known now
+    let value_references: Vec<u64> = model.model_variables.iter().map(|v| v.value_reference).collect();
+    for variable in model.model_variables.iter_mut() {
+        if let VariableType::Real { derivative: Some(state), .. } = &mut variable.variable_type {
+            *state = match (*state as usize).checked_sub(1).and_then(|index| value_references.get(index)) {
+                Some(value_reference) => *value_reference,
+                None => {
+                    return Err(ParseError::InvalidAttribute {
+                        element: "Real".to_string(),
+                        attribute: "derivative".to_string(),
+                        value: state.to_string(),
+                    })
+                }
+            };
+        }
+    }
+
+    Ok(model)
+}
+
+fn read_model_exchange(reader: &mut XmlReader, element: &BytesStart, empty: bool) -> Result<ModelExchange, ParseError> {
+    let attributes = Attributes::of(element)?;
+    Ok(ModelExchange {
+        model_identifier: attributes.required("modelIdentifier")?,
+        needs_execution_tool: attributes.flag("needsExecutionTool")?,
+        completed_integrator_step_not_needed: attributes.flag("completedIntegratorStepNotNeeded")?,
+        can_be_instantiated_only_once_per_process: attributes.flag("canBeInstantiatedOnlyOncePerProcess")?,
+        can_not_use_memory_management_functions: attributes.flag("canNotUseMemoryManagementFunctions")?,
+        can_get_and_set_fmu_state: attributes.flag("canGetAndSetFMUstate")?,
+        can_serialize_fmu_state: attributes.flag("canSerializeFMUstate")?,
+        provides_directional_derivative: attributes.flag("providesDirectionalDerivative")?,
+        source_files: read_source_files(reader, empty)?,
+    })
+}
+
+fn read_co_simulation(reader: &mut XmlReader, element: &BytesStart, empty: bool) -> Result<CoSimulation, ParseError> {
+    let attributes = Attributes::of(element)?;
+    Ok(CoSimulation {
+        model_identifier: attributes.required("modelIdentifier")?,
+        needs_execution_tool: attributes.flag("needsExecutionTool")?,
+        can_handle_variable_communication_step_size: attributes.flag("canHandleVariableCommunicationStepSize")?,
+        can_interpolate_inputs: attributes.flag("canInterpolateInputs")?,
+        max_output_derivative_order: attributes.parse("maxOutputDerivativeOrder")?.unwrap_or(0),
+        can_run_asynchronuously: attributes.flag("canRunAsynchronuously")?,
+        can_be_instantiated_only_once_per_process: attributes.flag("canBeInstantiatedOnlyOncePerProcess")?,
+        can_not_use_memory_management_functions: attributes.flag("canNotUseMemoryManagementFunctions")?,
+        can_get_and_set_fmu_state: attributes.flag("canGetAndSetFMUstate")?,
+        can_serialize_fmu_state: attributes.flag("canSerializeFMUstate")?,
+        provides_directional_derivative: attributes.flag("providesDirectionalDerivative")?,
+        source_files: read_source_files(reader, empty)?,
+    })
+}
+
+/// The files of the `<SourceFiles>` in `<ModelExchange>` or `<CoSimulation>`.
+fn read_source_files(reader: &mut XmlReader, empty: bool) -> Result<Vec<String>, ParseError> {
+    let mut files = Vec::new();
+    children(reader, empty, |reader, element, empty| {
+        if element.name().as_ref() != b"SourceFiles" {
+            return skip(reader, element, empty);
+        }
+        files = read_list(reader, empty, "File", |reader, element, empty| {
+            let name = Attributes::of(element)?.required("name")?;
+            skip(reader, element, empty)?;
+            Ok(name)
+        })?;
+        Ok(())
+    })?;
+    Ok(files)
+}
+
+fn read_unit(reader: &mut XmlReader, element: &BytesStart, empty: bool) -> Result<UnitDefinition, ParseError> {
+    let name = Attributes::of(element)?.required("name")?;
+    let mut base_unit = None;
+    let mut display_units = Vec::new();
+    children(reader, empty, |reader, element, empty| {
+        let attributes = Attributes::of(element)?;
+        let factor = attributes.parse("factor")?.unwrap_or(1.0);
+        let offset = attributes.parse("offset")?.unwrap_or(0.0);
+        match element.name().as_ref() {
+            b"BaseUnit" => {
+                let exponent = |name| Ok::<i32, ParseError>(attributes.parse(name)?.unwrap_or(0));
+                base_unit = Some(BaseUnit {
+                    kg: exponent("kg")?,
+                    m: exponent("m")?,
+                    s: exponent("s")?,
+                    a: exponent("A")?,
+                    k: exponent("K")?,
+                    mol: exponent("mol")?,
+                    cd: exponent("cd")?,
+                    rad: exponent("rad")?,
+                    factor,
+                    offset,
+                });
+            }
+            b"DisplayUnit" => display_units.push(DisplayUnitDefinition {
+                name: attributes.required("name")?,
+                factor,
+                offset,
+            }),
+            _ => {}
+        }
+        skip(reader, element, empty)
+    })?;
+
+    Ok(UnitDefinition {
+        name,
+        base_unit,
+        display_units,
+    })
+}
+
+fn read_simple_type(reader: &mut XmlReader, element: &BytesStart, empty: bool) -> Result<SimpleType, ParseError> {
+    let attributes = Attributes::of(element)?;
+    let mut kind = None;
+    children(reader, empty, |reader, element, empty| {
+        let attributes = Attributes::of(element)?;
+        let quantity = attributes.get("quantity");
+        kind = Some(match element.name().as_ref() {
+            b"Real" => SimpleTypeKind::Real {
+                quantity,
+                unit: attributes.get("unit"),
+                display_unit: attributes.get("displayUnit"),
+                min: attributes.parse("min")?,
+                max: attributes.parse("max")?,
+                nominal: attributes.parse("nominal")?,
+            },
+            b"Integer" => SimpleTypeKind::Integer {
+                quantity,
+                min: attributes.parse("min")?,
+                max: attributes.parse("max")?,
+            },
+            b"Boolean" => SimpleTypeKind::Boolean,
+            b"String" => SimpleTypeKind::String,
+            b"Enumeration" => {
+                let items = read_list(reader, empty, "Item", |reader, element, empty| {
+                    let attributes = Attributes::of(element)?;
+                    skip(reader, element, empty)?;
+                    Ok(Item {
+                        name: attributes.required("name")?,
+                        value: attributes.parse_required("value")?,
+                        description: attributes.get("description").unwrap_or_default(),
+                    })
+                })?;
+                kind = Some(SimpleTypeKind::Enumeration { quantity, items });
+                return Ok(());
+            }
+            _ => return skip(reader, element, empty),
+        });
+        skip(reader, element, empty)
+    })?;
+
+    Ok(SimpleType {
+        name: attributes.required("name")?,
+        description: attributes.get("description").unwrap_or_default(),
+        kind: kind.ok_or_else(|| ParseError::Xml(format!("the type {} has no type element", attributes.get("name").unwrap_or_default())))?,
+    })
+}
+
+/// A `<ScalarVariable>`, with the index of the state as the `derivative` of Real variables.
+fn read_scalar_variable(
+    reader: &mut XmlReader,
+    element: &BytesStart,
+    empty: bool,
+    units: &[UnitDefinition],
+    types: &[SimpleType],
+) -> Result<ScalarVariable, ParseError> {
+    let attributes = Attributes::of(element)?;
+    let name = attributes.required("name")?;
+    let mut variable_type = None;
+    children(reader, empty, |reader, element, empty| {
+        let attributes = Attributes::of(element)?;
+        let start = attributes.get("start");
+        let ty = match element.name().as_ref() {
+            b"Real" => VariableType::Real {
+                unit: attributes
+                    .get("unit")
+                    .map(|name| units.iter().find(|unit| unit.name == name).cloned().unwrap_or(UnitDefinition::named(&name))),
+                display_unit: attributes.get("displayUnit"),
+                min: attributes.parse("min")?,
+                max: attributes.parse("max")?,
+                nominal: attributes.parse("nominal")?,
+                derivative: attributes.parse("derivative")?,
+            },
+            b"Integer" => VariableType::Integer {
+                min: attributes.parse("min")?,
+                max: attributes.parse("max")?,
+            },
+            b"Boolean" => VariableType::Boolean,
+            b"String" => VariableType::String,
+            b"Enumeration" => {
+                let declared_type = attributes.required("declaredType")?;
+                match types.iter().find(|t| t.name == declared_type) {
+                    Some(declared_type) => VariableType::Enumeration {
+                        declared_type: declared_type.clone(),
+                    },
+                    None => return Err(attributes.invalid("declaredType", &declared_type)),
+                }
+            }
+            _ => return skip(reader, element, empty),
+        };
+        variable_type = Some((ty, start));
+        skip(reader, element, empty)
+    })?;
+    let (variable_type, start) = variable_type.ok_or_else(|| ParseError::Xml(format!("the variable {} has no type element", name)))?;
+
+    Ok(ScalarVariable {
+        name,
+        value_reference: attributes.parse_required("valueReference")?,
+        description: attributes.get("description").unwrap_or_default(),
+        causality: attributes.parse_with("causality", Causality::from_name)?.unwrap_or(Causality::Local),
+        variability: attributes.parse_with("variability", Variability::from_name)?.unwrap_or(Variability::Continuous),
+        initial: attributes.parse_with("initial", Initial::from_name)?,
+        variable_type,
+        start,
+        dependencies: None,
+    })
+}
+
+fn read_unknown(reader: &mut XmlReader, element: &BytesStart, empty: bool) -> Result<Unknown, ParseError> {
+    let attributes = Attributes::of(element)?;
+    skip(reader, element, empty)?;
+
+    let indices: Option<Vec<usize>> =
+        attributes.parse_with("dependencies", |value| value.split_whitespace().map(|index| index.parse().ok()).collect())?;
+    let kinds: Option<Vec<DependencyKind>> =
+        attributes.parse_with("dependenciesKind", |value| value.split_whitespace().map(DependencyKind::from_name).collect())?;
+    let dependencies = match (indices, kinds) {
+        (Some(indices), None) => Some(indices.into_iter().map(|index| (index, DependencyKind::Dependent)).collect()),
+        (Some(indices), Some(kinds)) if indices.len() == kinds.len() => Some(indices.into_iter().zip(kinds).collect()),
+        (None, None) => None,
+        _ => return Err(attributes.invalid("dependenciesKind", &attributes.get("dependenciesKind").unwrap_or_default())),
+    };
+
+    Ok(Unknown {
+        index: attributes.parse_required("index")?,
+        dependencies,
+    })
+}
+
+/// A GUID that identifies the interface of a model: its name and the name, value reference, type,
+/// causality and variability of each of its variables, and which of them are derivatives. It
+/// changes exactly when one of these does, and is the same on every machine and compiler.
+pub fn interface_guid(model_name: &str, variables: &[ScalarVariable]) -> String {
+    let mut interface = format!("{}\n", model_name);
+    for variable in variables {
+        let type_details = match &variable.variable_type {
+            VariableType::Real { derivative: Some(state), .. } => format!("der {}", state),
+            VariableType::Enumeration { declared_type } => declared_type.name.clone(),
+            _ => String::new(),
+        };
+        interface.push_str(&format!(
+            "{} {} {} {} {} {}\n",
+            variable.name,
+            variable.value_reference,
+            variable.variable_type.name(),
+            type_details,
+            variable.causality.name(),
+            variable.variability.name()
+        ));
+    }
+
+    // 128 bit FNV-1a, which unlike the hashers of std is specified and won't change
+    let mut hash: u128 = 0x6c62272e07bb014262b821756295c58d;
+    for byte in interface.bytes() {
+        hash ^= byte as u128;
+        hash = hash.wrapping_mul(0x0000000001000000000000000000013b);
+    }
+
+    // Written as a version 8 UUID, which leaves everything but the version and variant bits to
+    // the application
+    let hash = (hash & !(0xf << 76)) | (0x8 << 76);
+    let hash = (hash & !(0x3 << 62)) | (0x2 << 62);
+    format!(
+        "{{{:08x}-{:04x}-{:04x}-{:04x}-{:012x}}}",
+        hash >> 96,
+        (hash >> 80) & 0xffff,
+        (hash >> 64) & 0xffff,
+        (hash >> 48) & 0xffff,
+        hash & 0xffff_ffff_ffff
+    )
+}
+
+/// The index of the variable with the given value reference, counting from 1.
+fn index_of(variables: &[ScalarVariable], value_reference: u64) -> Option<usize> {
+    variables
+        .iter()
+        .position(|v| v.value_reference == value_reference)
+        .map(|index| index + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variable(name: &str, value_reference: u64, causality: Causality, variable_type: VariableType) -> ScalarVariable {
+        ScalarVariable {
+            name: name.to_string(),
+            value_reference,
+            description: String::new(),
+            causality,
+            variability: Variability::Continuous,
+            initial: None,
+            variable_type,
+            start: None,
+            dependencies: None,
+        }
+    }
+
+    #[test]
+    fn test_model_structure_indices() {
+        let real = |derivative| VariableType::Real {
+            unit: Some(Unit::named("m").into()),
+            display_unit: None,
+            min: None,
+            max: None,
+            nominal: None,
+            derivative,
+        };
+        let variables = vec![
+            variable("y", 7, Causality::Output, real(None)),
+            variable("inverter.x", 12, Causality::Local, real(None)),
+            variable("inverter.der(x)", 13, Causality::Output, real(Some(12))),
+        ];
+
+        let xml = ModelDescription::new("Model", "", variables).unwrap().to_xml();
+        assert!(xml.contains(r#"name="inverter.der(x)" valueReference="13""#));
+        assert!(xml.contains(r#"<Real unit="m" derivative="2"/>"#));
+
+        assert!(xml.contains("<Outputs>\n            <Unknown index=\"1\"/>"));
+        assert!(xml.contains(r#"<Unknown index="3"/>"#));
+        assert!(xml.contains("<Derivatives>\n            <Unknown index=\"3\"/>"));
+        assert!(!xml.contains("<InitialUnknowns>"));
+
+        assert_eq!(xml.matches("<Unit ").count(), 1);
+        assert!(!xml.contains("<TypeDefinitions>"));
+    }
+
+    #[test]
+    fn test_value_reference_collision() {
+        let variables = vec![
+            variable("x", 3, Causality::Local, VariableType::Boolean),
+            variable("inverter.x", 3, Causality::Local, VariableType::Boolean),
+        ];
+        let error = ModelDescription::new("Model", "", variables).unwrap_err();
+        assert_eq!(
+            error,
+            ModelError::ValueReferenceCollision { first: "x".to_string(), second: "inverter.x".to_string(), value_reference: 3 }
+        );
+        assert_eq!(error.to_string(), "x and inverter.x have the same value reference 3");
+    }
+
+    #[test]
+    fn test_interface_guid() {
+        let mut variables = vec![
+            variable("u", 0, Causality::Input, VariableType::Boolean),
+            variable("y", 1, Causality::Output, VariableType::Integer { min: None, max: None }),
+        ];
+        let guid = interface_guid("Model", &variables);
+        assert_eq!(guid, "{be27792c-13d0-800b-b1e5-ee2ac31067fe}");
+
+        variables[1].description = "changed".to_string();
+        variables[1].start = Some("1".to_string());
+        assert_eq!(interface_guid("Model", &variables), guid);
+        assert_ne!(interface_guid("Other", &variables), guid);
+
+        variables[1].value_reference = 2;
+        assert_ne!(interface_guid("Model", &variables), guid);
+    }
+
+    #[test]
+    fn test_start_values() {
+        assert_eq!(0.1f32.start_value(), "0.1");
+        assert_eq!(1.0f64.start_value(), "1.0");
+        assert_eq!(1e-7f64.start_value(), "1e-7");
+        assert_eq!(f64::NEG_INFINITY.start_value(), "-INF");
+        assert_eq!(f64::NAN.start_value(), "NaN");
+        assert_eq!((-3i8).start_value(), "-3");
+        assert_eq!(true.start_value(), "true");
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let real = |derivative| VariableType::Real { unit: None, display_unit: None, min: None, max: None, nominal: None, derivative };
+        let dependency = |name: &str, kind| Dependency { name: name.to_string(), kind };
+        let mut variables = vec![
+            variable("u[1]", 0, Causality::Input, real(None)),
+            variable("u[2]", 1, Causality::Input, real(None)),
+            variable("k", 2, Causality::Parameter, real(None)),
+            variable("x", 3, Causality::Local, real(None)),
+            variable("der(x)", 4, Causality::Local, real(Some(3))),
+            variable("y", 5, Causality::Output, real(None)),
+            variable("z", 6, Causality::Output, real(None)),
+        ];
+        variables[5].initial = Some(Initial::Calculated);
+        variables[5].dependencies = Some(vec![dependency("x", DependencyKind::Fixed), dependency("u", DependencyKind::Dependent)]);
+        variables[6].dependencies = Some(vec![]);
+
+        let xml = ModelDescription::new("Model", "", variables.clone()).unwrap().to_xml();
+        assert!(xml.contains(r#"<Unknown index="6" dependencies="1 2 4" dependenciesKind="dependent dependent fixed"/>"#));
+        assert!(xml.contains(r#"<Unknown index="7" dependencies=""/>"#));
+        assert!(xml.contains("<InitialUnknowns>\n            <Unknown index=\"6\" dependencies=\"1 2 4\"/>"));
+
+        // A state that is an unknown of the initialization isn't a known there
+        variables[3].initial = Some(Initial::Approx);
+        let xml = ModelDescription::new("Model", "", variables).unwrap().to_xml();
+        assert!(xml.contains("<InitialUnknowns>\n            <Unknown index=\"4\"/>\n            <Unknown index=\"6\"/>"));
+    }
+
+    #[test]
+    fn test_dependency_on_parameter() {
+        let real = VariableType::Real { unit: None, display_unit: None, min: None, max: None, nominal: None, derivative: None };
+        let mut variables = vec![
+            variable("k", 0, Causality::Parameter, real.clone()),
+            variable("y", 1, Causality::Output, real),
+        ];
+        variables[1].dependencies = Some(vec![Dependency { name: "k".to_string(), kind: DependencyKind::Fixed }]);
+        assert_eq!(
+            ModelDescription::new("Model", "", variables.clone()),
+            Err(ModelError::InvalidDependency { variable: "y".to_string(), dependency: "k".to_string() })
+        );
+
+        variables[1].dependencies = Some(vec![Dependency { name: "u".to_string(), kind: DependencyKind::Dependent }]);
+        assert_eq!(
+            ModelDescription::new("Model", "", variables),
+            Err(ModelError::UnknownDependency { variable: "y".to_string(), dependency: "u".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_unit_definitions() {
+        use crate::units::{lookup, SI_UNITS};
+
+        let real = |unit: Option<&'static str>, display_unit: Option<&str>| VariableType::Real {
+            unit: unit.map(|name| lookup(name, &[SI_UNITS]).into()),
+            display_unit: display_unit.map(str::to_string),
+            min: None,
+            max: None,
+            nominal: None,
+            derivative: None,
+        };
+        let variables = vec![
+            variable("v", 0, Causality::Output, real(Some("V"), Some("kV"))),
+            variable("t", 1, Causality::Output, real(Some("K"), None)),
+            variable("x", 2, Causality::Output, real(Some("pu"), None)),
+            variable("n", 3, Causality::Output, real(None, None)),
+            variable("w", 4, Causality::Output, real(Some("V"), None)),
+        ];
+
+        let xml = ModelDescription::new("Model", "", variables.clone()).unwrap().to_xml();
+        let names: Vec<&str> = xml.split("<Unit name=\"").skip(1).map(|u| &u[..u.find('"').unwrap()]).collect();
+        assert_eq!(names, ["K", "V", "pu"]);
+        assert!(xml.contains(r#"<BaseUnit K="1"/>"#));
+        assert!(xml.contains(r#"<DisplayUnit name="degC" offset="-273.15"/>"#));
+        assert!(xml.contains(r#"<BaseUnit kg="1" m="2" s="-3" A="-1"/>"#));
+        assert!(xml.contains(r#"<DisplayUnit name="kV" factor="0.001"/>"#));
+        assert!(xml.contains(r#"<Unit name="pu"/>"#));
+
+        assert!(xml.contains(r#"<Real unit="V" displayUnit="kV"/>"#));
+        assert!(xml.contains("<Real/>"));
+        assert!(!ModelDescription::new("Model", "", variables[3..4].to_vec()).unwrap().to_xml().contains("<UnitDefinitions>"));
+
+        let mut invalid = variables.clone();
+        invalid[1] = variable("t", 1, Causality::Output, real(Some("K"), Some("kV")));
+        assert_eq!(
+            ModelDescription::new("Model", "", invalid.clone()),
+            Err(ModelError::UndeclaredDisplayUnit { variable: "t".to_string(), display_unit: "kV".to_string() })
+        );
+
+        invalid[1].variable_type = VariableType::Real {
+            unit: Some(UnitDefinition::named("V")),
+            display_unit: None,
+            min: None,
+            max: None,
+            nominal: None,
+            derivative: None,
+        };
+        assert_eq!(
+            ModelDescription::new("Model", "", invalid),
+            Err(ModelError::ConflictingUnit { variable: "t".to_string(), unit: "V".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        use crate::units::{lookup, SI_UNITS};
+
+        let solver = SimpleType {
+            name: "Solver".to_string(),
+            description: "Integration method".to_string(),
+            kind: SimpleTypeKind::Enumeration {
+                quantity: None,
+                items: vec![
+                    Item { name: "Euler".to_string(), value: 1, description: String::new() },
+                    Item { name: "Rk4".to_string(), value: 4, description: "Runge–Kutta".to_string() },
+                ],
+            },
+        };
+        let real = |unit, derivative| VariableType::Real {
+            unit: Some(lookup(unit, &[SI_UNITS]).into()),
+            display_unit: None,
+            min: Some(-1.5),
+            max: None,
+            nominal: None,
+            derivative,
+        };
+        let mut variables = vec![
+            variable("x", 4, Causality::Output, real("m", None)),
+            variable("der(x)", 5, Causality::Local, real("m/s", Some(4))),
+            variable("solver", 6, Causality::Parameter, VariableType::Enumeration { declared_type: solver }),
+            variable("label", 7, Causality::Parameter, VariableType::String),
+            variable("u", 8, Causality::Input, real("m", None)),
+        ];
+        variables[0].initial = Some(Initial::Exact);
+        variables[0].start = Some("0.5".to_string());
+        variables[0].dependencies = Some(vec![Dependency { name: "u".to_string(), kind: DependencyKind::Fixed }]);
+        variables[2].variability = Variability::Fixed;
+        variables[2].start = Some("4".to_string());
+        variables[3].description = "Temperatur in °C & <K>".to_string();
+        variables[3].variability = Variability::Fixed;
+        variables[3].start = Some("ä \"ß\"".to_string());
+
+        let mut model = ModelDescription::new("Model", "{8c4e810f-3df3-4a00-8276-176fa3c9f000}", variables).unwrap();
+        model.description = "Grüße".to_string();
+        model.author = Some("me".to_string());
+        model.variable_naming_convention = VariableNamingConvention::Structured;
+        model.model_exchange = Some(ModelExchange {
+            model_identifier: "Model".to_string(),
+            provides_directional_derivative: true,
+            ..Default::default()
+        });
+        model.co_simulation = Some(CoSimulation {
+            model_identifier: "Model".to_string(),
+            max_output_derivative_order: 2,
+            can_get_and_set_fmu_state: true,
+            source_files: vec!["model.c".to_string()],
+            ..Default::default()
+        });
+        model.log_categories = vec![LogCategory { name: "logEvents".to_string(), description: String::new() }];
+        model.default_experiment = Some(DefaultExperiment { stop_time: Some(10.0), step_size: Some(1e-3), ..Default::default() });
+        model.vendor_annotations = vec![Tool { name: "tool".to_string(), content: r#"<Option key="a"/>"#.to_string() }];
+
+        let xml = model.to_xml();
+        assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<fmiModelDescription fmiVersion=\"2.0\""));
+        assert!(xml.lines().all(|line| !line.trim().is_empty()));
+        assert!(xml.contains("<Tool name=\"tool\"><Option key=\"a\"/></Tool>"));
+        assert!(xml.contains(r#"<Real unit="m/s" min="-1.5" derivative="1"/>"#));
+        assert!(xml.contains(r#"<DefaultExperiment stopTime="10.0" stepSize="0.001"/>"#));
+        assert!(xml.contains(r#"<Unknown index="1" dependencies="5" dependenciesKind="fixed"/>"#));
+
+        // Declared dependencies only end up in the model structure
+        let mut expected = model.clone();
+        expected.model_variables[0].dependencies = None;
+        let parsed = ModelDescription::from_xml(&xml).unwrap();
+        assert_eq!(parsed, expected);
+        assert_eq!(parsed.to_xml(), xml);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse = |body: &str| {
+            ModelDescription::from_xml(&format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?><fmiModelDescription fmiVersion="2.0" modelName="M" guid="{{1}}">{}</fmiModelDescription>"#,
+                body
+            ))
+        };
+
+        let model = parse(r#"<ModelVariables><ScalarVariable name="u" valueReference="0" causality="input"><Real start="1"/></ScalarVariable></ModelVariables>"#).unwrap();
+        assert_eq!(model.model_variables[0].causality, Causality::Input);
+        assert_eq!(model.model_variables[0].variability, Variability::Continuous);
+        assert_eq!(model.model_variables[0].start.as_deref(), Some("1"));
+
+        assert!(matches!(
+            ModelDescription::from_xml(r#"<fmiModelDescription fmiVersion="3.0" modelName="M" guid="1"/>"#),
+            Err(ParseError::UnsupportedVersion(version)) if version == "3.0"
+        ));
+        assert!(matches!(ModelDescription::from_xml("<fmiModelDescription"), Err(ParseError::Xml(_))));
+        assert_eq!(
+            parse(r#"<CoSimulation canInterpolateInputs="true"/>"#),
+            Err(ParseError::MissingAttribute { element: "CoSimulation".to_string(), attribute: "modelIdentifier".to_string() })
+        );
+        assert_eq!(
+            parse(r#"<CoSimulation modelIdentifier="M" canInterpolateInputs="yes"/>"#),
+            Err(ParseError::InvalidAttribute {
+                element: "CoSimulation".to_string(),
+                attribute: "canInterpolateInputs".to_string(),
+                value: "yes".to_string(),
+            })
+        );
+        assert!(matches!(
+            parse(r#"<ModelVariables><ScalarVariable name="m" valueReference="0"><Enumeration declaredType="Mode"/></ScalarVariable></ModelVariables>"#),
+            Err(ParseError::InvalidAttribute { attribute, .. }) if attribute == "declaredType"
+        ));
+        assert!(matches!(
+            parse(r#"<ModelVariables><ScalarVariable name="x" valueReference="0"><Real derivative="2"/></ScalarVariable></ModelVariables>"#),
+            Err(ParseError::InvalidAttribute { attribute, .. }) if attribute == "derivative"
+        ));
+    }
+}
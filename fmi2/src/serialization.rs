@@ -0,0 +1,135 @@
+//! Byte format used by `fmi2SerializeFMUstate` and `fmi2DeSerializeFMUstate`.
+//!
+//! A serialized FMU state is laid out as (all integers little endian):
+//!
+//! | bytes | content                                    |
+//! |-------|--------------------------------------------|
+//! | 8     | magic `FMI2STAT`                           |
+//! | 4     | format version, see `FORMAT_VERSION`       |
+//! | 4     | length of the model GUID                   |
+//! | n     | model GUID (UTF-8)                         |
+//! | 8     | length of the payload                      |
+//! | m     | the model state serialized as JSON         |
+//!
+//! The GUID is checked on deserialization, so a state written by a different build of the
+//! model is rejected instead of being loaded into the wrong struct layout.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+
+pub const MAGIC: &[u8; 8] = b"FMI2STAT";
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum SerializationError {
+    /// The buffer does not start with `MAGIC` or is cut short.
+    Malformed,
+    /// The buffer was written with a format version this crate can't read.
+    UnsupportedVersion(u32),
+    /// The buffer was written by a model with a different GUID.
+    GuidMismatch { expected: String, found: String },
+    /// The payload could not be (de)serialized.
+    Payload(String),
+}
+
+impl fmt::Display for SerializationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SerializationError::Malformed => write!(f, "malformed serialized FMU state"),
+            SerializationError::UnsupportedVersion(v) => {
+                write!(f, "unsupported serialized FMU state version {} (expected {})", v, FORMAT_VERSION)
+            }
+            SerializationError::GuidMismatch { expected, found } => {
+                write!(f, "serialized FMU state belongs to model {} (expected {})", found, expected)
+            }
+            SerializationError::Payload(e) => write!(f, "could not (de)serialize FMU state: {}", e),
+        }
+    }
+}
+
+pub fn serialize_state<T: Serialize>(guid: &str, state: &T) -> Result<Vec<u8>, SerializationError> {
+    let payload = serde_json::to_vec(state).map_err(|e| SerializationError::Payload(e.to_string()))?;
+
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 16 + guid.len() + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(guid.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(guid.as_bytes());
+    bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+pub fn deserialize_state<T: DeserializeOwned>(guid: &str, bytes: &[u8]) -> Result<T, SerializationError> {
+    let mut reader = Reader { bytes };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(SerializationError::Malformed);
+    }
+
+    let version = u32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(SerializationError::UnsupportedVersion(version));
+    }
+
+    let guid_len = u32::from_le_bytes(reader.take(4)?.try_into().unwrap()) as usize;
+    let found = reader.take(guid_len)?;
+    if found != guid.as_bytes() {
+        return Err(SerializationError::GuidMismatch {
+            expected: guid.to_string(),
+            found: String::from_utf8_lossy(found).into_owned(),
+        });
+    }
+
+    let payload_len = u64::from_le_bytes(reader.take(8)?.try_into().unwrap()) as usize;
+    let payload = reader.take(payload_len)?;
+    serde_json::from_slice(payload).map_err(|e| SerializationError::Payload(e.to_string()))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SerializationError> {
+        if n > self.bytes.len() {
+            return Err(SerializationError::Malformed);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUID: &str = "{21d9f232-b090-4c79-933f-33da939b5934}";
+
+    #[test]
+    fn test_roundtrip() {
+        let bytes = serialize_state(GUID, &(1.5f64, true)).unwrap();
+        let state: (f64, bool) = deserialize_state(GUID, &bytes).unwrap();
+        assert_eq!(state, (1.5, true));
+    }
+
+    #[test]
+    fn test_rejects_other_model() {
+        let bytes = serialize_state("{other}", &1.5f64).unwrap();
+        assert!(matches!(
+            deserialize_state::<f64>(GUID, &bytes),
+            Err(SerializationError::GuidMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_rejects_truncated_buffer() {
+        let bytes = serialize_state(GUID, &1.5f64).unwrap();
+        assert_eq!(
+            deserialize_state::<f64>(GUID, &bytes[..bytes.len() - 1]),
+            Err(SerializationError::Malformed)
+        );
+    }
+}
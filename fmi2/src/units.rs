@@ -0,0 +1,186 @@
+//! Unit definitions for `<UnitDefinitions>`.
+//!
+//! A unit is declared once as a `Unit`, with the SI base units it is made of and the units it can
+//! be displayed in, and collected into a registry: a slice of units. The derive looks up the
+//! `unit` of every Real variable in the registry given with `#[fmi_model(units = "...")]` and
+//! then in `SI_UNITS`, so registries can be shared between models. Units that aren't found are
+//! written without a base unit.
+//!
+//! ```
+//! use fmi2::units::{BaseUnit, DisplayUnit, Unit};
+//!
+//! pub const UNITS: &[Unit] = &[Unit {
+//!     name: "rpm",
+//!     base_unit: Some(BaseUnit::DIMENSIONLESS.rad(1).s(-1).factor(0.104_719_755_119_659_78)),
+//!     display_units: &[],
+//! }];
+//! ```
+
+/// The exponents of the SI base units a unit is made of, and how a value converts to them:
+/// `value_in_base_units = factor * value + offset`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BaseUnit {
+    pub kg: i32,
+    pub m: i32,
+    pub s: i32,
+    pub a: i32,
+    pub k: i32,
+    pub mol: i32,
+    pub cd: i32,
+    pub rad: i32,
+    pub factor: f64,
+    pub offset: f64,
+}
+
+impl BaseUnit {
+    /// A unit without dimension and conversion, to build other base units from.
+    pub const DIMENSIONLESS: BaseUnit = BaseUnit {
+        kg: 0,
+        m: 0,
+        s: 0,
+        a: 0,
+        k: 0,
+        mol: 0,
+        cd: 0,
+        rad: 0,
+        factor: 1.0,
+        offset: 0.0,
+    };
+
+    pub const fn kg(self, kg: i32) -> BaseUnit {
+        BaseUnit { kg, ..self }
+    }
+
+    pub const fn m(self, m: i32) -> BaseUnit {
+        BaseUnit { m, ..self }
+    }
+
+    pub const fn s(self, s: i32) -> BaseUnit {
+        BaseUnit { s, ..self }
+    }
+
+    pub const fn a(self, a: i32) -> BaseUnit {
+        BaseUnit { a, ..self }
+    }
+
+    pub const fn k(self, k: i32) -> BaseUnit {
+        BaseUnit { k, ..self }
+    }
+
+    pub const fn mol(self, mol: i32) -> BaseUnit {
+        BaseUnit { mol, ..self }
+    }
+
+    pub const fn cd(self, cd: i32) -> BaseUnit {
+        BaseUnit { cd, ..self }
+    }
+
+    pub const fn rad(self, rad: i32) -> BaseUnit {
+        BaseUnit { rad, ..self }
+    }
+
+    pub const fn factor(self, factor: f64) -> BaseUnit {
+        BaseUnit { factor, ..self }
+    }
+
+    pub const fn offset(self, offset: f64) -> BaseUnit {
+        BaseUnit { offset, ..self }
+    }
+}
+
+/// A unit a value can be shown in: `value_in_display_unit = factor * value + offset`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisplayUnit {
+    pub name: &'static str,
+    pub factor: f64,
+    pub offset: f64,
+}
+
+impl DisplayUnit {
+    pub const fn new(name: &'static str, factor: f64, offset: f64) -> DisplayUnit {
+        DisplayUnit { name, factor, offset }
+    }
+}
+
+/// A `<Unit>` of the model description.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unit {
+    pub name: &'static str,
+    pub base_unit: Option<BaseUnit>,
+    pub display_units: &'static [DisplayUnit],
+}
+
+impl Unit {
+    /// A unit that is only known by its name.
+    pub const fn named(name: &'static str) -> Unit {
+        Unit {
+            name,
+            base_unit: None,
+            display_units: &[],
+        }
+    }
+
+    const fn si(name: &'static str, base_unit: BaseUnit, display_units: &'static [DisplayUnit]) -> Unit {
+        Unit {
+            name,
+            base_unit: Some(base_unit),
+            display_units,
+        }
+    }
+}
+
+const ONE: BaseUnit = BaseUnit::DIMENSIONLESS;
+
+/// The SI base units and common derived units, used for the units that aren't in the registry of
+/// a model.
+pub const SI_UNITS: &[Unit] = &[
+    Unit::si("1", ONE, &[DisplayUnit::new("%", 100.0, 0.0)]),
+    Unit::si("kg", ONE.kg(1), &[DisplayUnit::new("g", 1e3, 0.0), DisplayUnit::new("t", 1e-3, 0.0)]),
+    Unit::si("m", ONE.m(1), &[DisplayUnit::new("mm", 1e3, 0.0), DisplayUnit::new("km", 1e-3, 0.0)]),
+    Unit::si("s", ONE.s(1), &[DisplayUnit::new("ms", 1e3, 0.0), DisplayUnit::new("min", 1.0 / 60.0, 0.0), DisplayUnit::new("h", 1.0 / 3600.0, 0.0)]),
+    Unit::si("A", ONE.a(1), &[DisplayUnit::new("mA", 1e3, 0.0), DisplayUnit::new("kA", 1e-3, 0.0)]),
+    Unit::si("K", ONE.k(1), &[DisplayUnit::new("degC", 1.0, -273.15)]),
+    Unit::si("mol", ONE.mol(1), &[]),
+    Unit::si("cd", ONE.cd(1), &[]),
+    Unit::si("rad", ONE.rad(1), &[DisplayUnit::new("deg", 180.0 / std::f64::consts::PI, 0.0)]),
+    Unit::si("Hz", ONE.s(-1), &[DisplayUnit::new("kHz", 1e-3, 0.0)]),
+    Unit::si("rad/s", ONE.rad(1).s(-1), &[DisplayUnit::new("rpm", 30.0 / std::f64::consts::PI, 0.0)]),
+    Unit::si("m/s", ONE.m(1).s(-1), &[DisplayUnit::new("km/h", 3.6, 0.0)]),
+    Unit::si("m/s2", ONE.m(1).s(-2), &[]),
+    Unit::si("N", ONE.kg(1).m(1).s(-2), &[DisplayUnit::new("kN", 1e-3, 0.0)]),
+    Unit::si("N.m", ONE.kg(1).m(2).s(-2), &[]),
+    Unit::si("Pa", ONE.kg(1).m(-1).s(-2), &[DisplayUnit::new("kPa", 1e-3, 0.0), DisplayUnit::new("bar", 1e-5, 0.0)]),
+    Unit::si("J", ONE.kg(1).m(2).s(-2), &[DisplayUnit::new("kWh", 1.0 / 3.6e6, 0.0)]),
+    Unit::si("W", ONE.kg(1).m(2).s(-3), &[DisplayUnit::new("kW", 1e-3, 0.0), DisplayUnit::new("MW", 1e-6, 0.0)]),
+    Unit::si("C", ONE.a(1).s(1), &[DisplayUnit::new("Ah", 1.0 / 3600.0, 0.0)]),
+    Unit::si("V", ONE.kg(1).m(2).s(-3).a(-1), &[DisplayUnit::new("mV", 1e3, 0.0), DisplayUnit::new("kV", 1e-3, 0.0)]),
+    Unit::si("Ohm", ONE.kg(1).m(2).s(-3).a(-2), &[DisplayUnit::new("kOhm", 1e-3, 0.0)]),
+    Unit::si("F", ONE.kg(-1).m(-2).s(4).a(2), &[DisplayUnit::new("uF", 1e6, 0.0)]),
+    Unit::si("H", ONE.kg(1).m(2).s(-2).a(-2), &[DisplayUnit::new("mH", 1e3, 0.0)]),
+    Unit::si("degC", ONE.k(1).offset(273.15), &[]),
+];
+
+/// The unit `name` from the first registry that has it, or a unit without definition.
+pub fn lookup(name: &'static str, registries: &[&[Unit]]) -> Unit {
+    registries
+        .iter()
+        .flat_map(|registry| registry.iter())
+        .find(|unit| unit.name == name)
+        .copied()
+        .unwrap_or(Unit::named(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        const UNITS: &[Unit] = &[Unit::named("V"), Unit::named("pu")];
+
+        assert_eq!(lookup("V", &[SI_UNITS]).base_unit, Some(ONE.kg(1).m(2).s(-3).a(-1)));
+        assert_eq!(lookup("V", &[UNITS, SI_UNITS]).base_unit, None);
+        assert_eq!(lookup("pu", &[SI_UNITS]), Unit::named("pu"));
+        assert_eq!(lookup("K", &[UNITS, SI_UNITS]).display_units[0].offset, -273.15);
+    }
+}
@@ -0,0 +1,343 @@
+//! Drives the `extern "C"` exports of a small derived model the way an FMI master would.
+
+#![allow(non_snake_case)]
+
+use fmi2::derive::*;
+use fmi2::{ContinuousModel, EventModel, FmiModelStructDerive, FmiResult, Instantiatable, Steppable};
+use fmi2_sys::*;
+use serde::{Deserialize, Serialize};
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+
+const GUID: &str = "{0c6b8b7e-3f52-4a55-9d8e-2f4a0b1f7a11}";
+
+/// Exponential decay `der(x) = -rate * x`, with a state event when `x` crosses `threshold`.
+#[derive(Serialize, Deserialize, FmiModelStructDerive, Debug, Clone)]
+#[fmi_model(
+    description = "Exponential decay",
+    guid = "{0c6b8b7e-3f52-4a55-9d8e-2f4a0b1f7a11}",
+    export = true,
+    event_indicators = 1
+)]
+pub struct Decay {
+    #[fmi_variable(id = 0, causality = "parameter", description = "Decay rate", unit = "1/s")]
+    rate: f64,
+
+    #[fmi_variable(id = 1, causality = "parameter", description = "Label")]
+    label: String,
+
+    #[fmi_variable(id = 2, causality = "output", unit = "1", depends_on = [])]
+    x: f64,
+
+    #[fmi_variable(id = 3, derivative = "x")]
+    der_x: f64,
+
+    #[fmi_variable(id = 4, causality = "parameter", description = "Event threshold")]
+    threshold: f64,
+
+    #[fmi_variable(id = 5, causality = "output", depends_on = [])]
+    events: i64,
+
+    time: f64,
+}
+
+impl Default for Decay {
+    fn default() -> Self {
+        Decay {
+            rate: 2.0,
+            label: String::new(),
+            x: 1.0,
+            der_x: 0.0,
+            threshold: 0.5,
+            events: 0,
+            time: 0.0,
+        }
+    }
+}
+
+impl Steppable for Decay {
+    fn do_step(&mut self, time: f64, step_size: f64) -> FmiResult {
+        self.time = time + step_size;
+        self.x *= (-self.rate * step_size).exp();
+        Ok(())
+    }
+}
+
+impl Instantiatable for Decay {}
+
+impl ContinuousModel for Decay {
+    fn set_time(&mut self, time: f64) {
+        self.time = time;
+    }
+
+    fn compute_derivatives(&mut self) -> FmiResult {
+        self.der_x = -self.rate * self.x;
+        Ok(())
+    }
+
+    fn completed_integrator_step(&mut self, step_info: &mut fmi2::IntegratorStepInfo) -> FmiResult {
+        step_info.terminate_simulation = self.x < 0.1;
+        Ok(())
+    }
+}
+
+impl EventModel for Decay {
+    fn event_indicators(&mut self, indicators: &mut [f64]) -> FmiResult {
+        indicators[0] = self.x - self.threshold;
+        Ok(())
+    }
+
+    fn update_discrete_states(&mut self, event_info: &mut fmi2::EventInfo) -> FmiResult {
+        // Halves the threshold until it is below `x` again, one event iteration per halving.
+        if self.x < self.threshold {
+            self.events += 1;
+            self.threshold /= 2.0;
+            event_info.new_discrete_states_needed = true;
+        }
+        Ok(())
+    }
+}
+
+const VR_RATE: fmi2ValueReference = 0;
+const VR_LABEL: fmi2ValueReference = 1;
+const VR_X: fmi2ValueReference = 2;
+const VR_EVENTS: fmi2ValueReference = 5;
+
+fn instantiate(fmu_type: fmi2Type) -> fmi2Component {
+    let name = CString::new("decay").unwrap();
+    let guid = CString::new(GUID).unwrap();
+    let c = fmi2Instantiate(name.as_ptr(), fmu_type, guid.as_ptr(), std::ptr::null(), std::ptr::null(), 0, 0);
+    assert!(!c.is_null());
+    c as fmi2Component
+}
+
+fn state(c: fmi2Component) -> fmi2::ModelState {
+    unsafe { fmi2::Instance::<Decay>::from_c_ptr(c) }.unwrap().state
+}
+
+fn get_real(c: fmi2Component, vr: fmi2ValueReference) -> f64 {
+    let mut value = [0.0];
+    assert_eq!(fmi2GetReal(c, [vr].as_ptr(), 1, value.as_mut_ptr()), fmi2Status_fmi2OK);
+    value[0]
+}
+
+fn initialize(c: fmi2Component) {
+    assert_eq!(fmi2SetupExperiment(c, 0, 0.0, 0.0, 0, 0.0), fmi2Status_fmi2OK);
+    assert_eq!(fmi2EnterInitializationMode(c), fmi2Status_fmi2OK);
+    assert_eq!(fmi2ExitInitializationMode(c), fmi2Status_fmi2OK);
+}
+
+#[test]
+fn test_co_simulation_lifecycle() {
+    let guid = CString::new("{wrong}").unwrap();
+    let name = CString::new("decay").unwrap();
+    let c = fmi2Instantiate(name.as_ptr(), fmi2Type_fmi2CoSimulation, guid.as_ptr(), std::ptr::null(), std::ptr::null(), 0, 0);
+    assert!(c.is_null());
+
+    let c = instantiate(fmi2Type_fmi2CoSimulation);
+    assert_eq!(state(c), fmi2::ModelState::Instantiated);
+
+    // Stepping before initialization is an invalid call and leaves the instance in Error.
+    assert_eq!(fmi2DoStep(c, 0.0, 0.1, 1), fmi2Status_fmi2Error);
+    assert_eq!(state(c), fmi2::ModelState::Error);
+    assert_eq!(fmi2Reset(c), fmi2Status_fmi2OK);
+    assert_eq!(state(c), fmi2::ModelState::Instantiated);
+
+    assert_eq!(fmi2SetReal(c, [VR_RATE].as_ptr(), 1, [1.0].as_ptr()), fmi2Status_fmi2OK);
+    initialize(c);
+    assert_eq!(state(c), fmi2::ModelState::StepComplete);
+    assert_eq!(fmi2DoStep(c, 0.0, 0.5, 1), fmi2Status_fmi2OK);
+    assert_eq!(fmi2DoStep(c, 0.5, 0.5, 1), fmi2Status_fmi2OK);
+    assert!((get_real(c, VR_X) - (-1.0f64).exp()).abs() < 1e-12);
+
+    // Parameters are fixed once initialized.
+    assert_eq!(fmi2SetReal(c, [VR_RATE].as_ptr(), 1, [3.0].as_ptr()), fmi2Status_fmi2Error);
+    assert_eq!(state(c), fmi2::ModelState::Error);
+    assert_eq!(fmi2Reset(c), fmi2Status_fmi2OK);
+    assert_eq!(fmi2EnterInitializationMode(c), fmi2Status_fmi2OK);
+    assert_eq!(get_real(c, VR_RATE), 2.0);
+    assert_eq!(fmi2ExitInitializationMode(c), fmi2Status_fmi2OK);
+    assert_eq!(fmi2Terminate(c), fmi2Status_fmi2OK);
+    assert_eq!(state(c), fmi2::ModelState::Terminated);
+    fmi2FreeInstance(c);
+}
+
+#[test]
+fn test_model_exchange_round_trip() {
+    let c = instantiate(fmi2Type_fmi2ModelExchange);
+    initialize(c);
+    assert_eq!(state(c), fmi2::ModelState::EventMode);
+    assert_eq!(fmi2EnterContinuousTimeMode(c), fmi2Status_fmi2OK);
+
+    assert_eq!(fmi2SetTime(c, 0.25), fmi2Status_fmi2OK);
+    let mut x = [0.0];
+    assert_eq!(fmi2GetContinuousStates(c, x.as_mut_ptr(), 1), fmi2Status_fmi2OK);
+    assert_eq!(x, [1.0]);
+    let mut dx = [0.0];
+    assert_eq!(fmi2GetDerivatives(c, dx.as_mut_ptr(), 1), fmi2Status_fmi2OK);
+    assert_eq!(dx, [-2.0]);
+
+    assert_eq!(fmi2SetContinuousStates(c, [0.75].as_ptr(), 1), fmi2Status_fmi2OK);
+    assert_eq!(fmi2GetDerivatives(c, dx.as_mut_ptr(), 1), fmi2Status_fmi2OK);
+    assert_eq!(dx, [-1.5]);
+    assert_eq!(get_real(c, VR_X), 0.75);
+
+    let mut enter_event_mode = 1;
+    let mut terminate_simulation = 1;
+    assert_eq!(
+        fmi2CompletedIntegratorStep(c, 1, &mut enter_event_mode, &mut terminate_simulation),
+        fmi2Status_fmi2OK
+    );
+    assert_eq!((enter_event_mode, terminate_simulation), (0, 0));
+
+    assert_eq!(fmi2SetContinuousStates(c, [0.05].as_ptr(), 1), fmi2Status_fmi2OK);
+    assert_eq!(
+        fmi2CompletedIntegratorStep(c, 1, &mut enter_event_mode, &mut terminate_simulation),
+        fmi2Status_fmi2OK
+    );
+    assert_eq!((enter_event_mode, terminate_simulation), (0, 1));
+
+    // The number of states has to match the model.
+    let mut too_many = [0.0; 2];
+    assert_eq!(fmi2GetDerivatives(c, too_many.as_mut_ptr(), 2), fmi2Status_fmi2Error);
+    assert_eq!(state(c), fmi2::ModelState::Error);
+
+    assert_eq!(fmi2Terminate(c), fmi2Status_fmi2Error);
+    fmi2FreeInstance(c);
+}
+
+#[test]
+fn test_event_iteration() {
+    let c = instantiate(fmi2Type_fmi2ModelExchange);
+    initialize(c);
+    assert_eq!(fmi2EnterContinuousTimeMode(c), fmi2Status_fmi2OK);
+
+    let mut z = [0.0f64];
+    assert_eq!(fmi2GetEventIndicators(c, z.as_mut_ptr(), 1), fmi2Status_fmi2OK);
+    assert_eq!(z, [0.5]);
+    assert_eq!(fmi2SetContinuousStates(c, [0.3].as_ptr(), 1), fmi2Status_fmi2OK);
+    assert_eq!(fmi2GetEventIndicators(c, z.as_mut_ptr(), 1), fmi2Status_fmi2OK);
+    assert!(z[0] < 0.0);
+
+    // Calling fmi2NewDiscreteStates outside of event mode is an invalid call.
+    let mut event_info = fmi2EventInfo {
+        newDiscreteStatesNeeded: 0,
+        terminateSimulation: 0,
+        nominalsOfContinuousStatesChanged: 0,
+        valuesOfContinuousStatesChanged: 0,
+        nextEventTimeDefined: 0,
+        nextEventTime: 0.0,
+    };
+    assert_eq!(fmi2NewDiscreteStates(c, &mut event_info), fmi2Status_fmi2Error);
+    assert_eq!(fmi2Reset(c), fmi2Status_fmi2OK);
+    initialize(c);
+    assert_eq!(fmi2EnterContinuousTimeMode(c), fmi2Status_fmi2OK);
+    assert_eq!(fmi2SetContinuousStates(c, [0.2].as_ptr(), 1), fmi2Status_fmi2OK);
+
+    assert_eq!(fmi2EnterEventMode(c), fmi2Status_fmi2OK);
+    let mut iterations = 0;
+    event_info.newDiscreteStatesNeeded = 1;
+    while event_info.newDiscreteStatesNeeded != 0 {
+        assert_eq!(fmi2NewDiscreteStates(c, &mut event_info), fmi2Status_fmi2OK);
+        iterations += 1;
+    }
+    assert_eq!(iterations, 3);
+    assert_eq!((event_info.terminateSimulation, event_info.nextEventTimeDefined), (0, 0));
+
+    let mut events = [0];
+    assert_eq!(fmi2GetInteger(c, [VR_EVENTS].as_ptr(), 1, events.as_mut_ptr()), fmi2Status_fmi2OK);
+    assert_eq!(events, [2]);
+
+    assert_eq!(fmi2EnterContinuousTimeMode(c), fmi2Status_fmi2OK);
+    assert_eq!(fmi2GetEventIndicators(c, z.as_mut_ptr(), 1), fmi2Status_fmi2OK);
+    assert!((z[0] - 0.075).abs() < 1e-12);
+    assert_eq!(fmi2Terminate(c), fmi2Status_fmi2OK);
+    fmi2FreeInstance(c);
+}
+
+#[test]
+fn test_fmu_state() {
+    let c = instantiate(fmi2Type_fmi2CoSimulation);
+    initialize(c);
+
+    let mut fmu_state: fmi2FMUstate = std::ptr::null_mut();
+    assert_eq!(fmi2GetFMUstate(c, &mut fmu_state), fmi2Status_fmi2OK);
+    assert!(!fmu_state.is_null());
+    assert_eq!(fmi2DoStep(c, 0.0, 0.5, 1), fmi2Status_fmi2OK);
+    assert!(get_real(c, VR_X) < 1.0);
+
+    assert_eq!(fmi2SetFMUstate(c, fmu_state), fmi2Status_fmi2OK);
+    assert_eq!(get_real(c, VR_X), 1.0);
+
+    // Getting the state again overwrites the one passed in
+    assert_eq!(fmi2DoStep(c, 0.0, 0.5, 1), fmi2Status_fmi2OK);
+    let stepped = get_real(c, VR_X);
+    let previous = fmu_state;
+    assert_eq!(fmi2GetFMUstate(c, &mut fmu_state), fmi2Status_fmi2OK);
+    assert_eq!(fmu_state, previous);
+    assert_eq!(fmi2DoStep(c, 0.5, 0.5, 1), fmi2Status_fmi2OK);
+    assert_eq!(fmi2SetFMUstate(c, fmu_state), fmi2Status_fmi2OK);
+    assert_eq!(get_real(c, VR_X), stepped);
+
+    assert_eq!(fmi2FreeFMUstate(c, &mut fmu_state), fmi2Status_fmi2OK);
+    assert!(fmu_state.is_null());
+    fmi2FreeInstance(c);
+}
+
+#[test]
+fn test_fmu_state_serialization() {
+    let c = instantiate(fmi2Type_fmi2CoSimulation);
+    initialize(c);
+
+    let mut fmu_state: fmi2FMUstate = std::ptr::null_mut();
+    assert_eq!(fmi2GetFMUstate(c, &mut fmu_state), fmi2Status_fmi2OK);
+
+    let mut size = 0;
+    assert_eq!(fmi2SerializedFMUstateSize(c, fmu_state, &mut size), fmi2Status_fmi2OK);
+    let mut serialized = vec![0 as fmi2Byte; size];
+    assert_eq!(fmi2SerializeFMUstate(c, fmu_state, serialized.as_mut_ptr(), size), fmi2Status_fmi2OK);
+    assert_eq!(fmi2SerializeFMUstate(c, fmu_state, serialized.as_mut_ptr(), size - 1), fmi2Status_fmi2Error);
+    assert_eq!(fmi2Reset(c), fmi2Status_fmi2OK);
+    initialize(c);
+
+    assert_eq!(fmi2DoStep(c, 0.0, 0.5, 1), fmi2Status_fmi2OK);
+    let mut restored: fmi2FMUstate = std::ptr::null_mut();
+    assert_eq!(fmi2DeSerializeFMUstate(c, serialized.as_ptr(), size, &mut restored), fmi2Status_fmi2OK);
+    assert_eq!(fmi2SetFMUstate(c, restored), fmi2Status_fmi2OK);
+    assert_eq!(get_real(c, VR_X), 1.0);
+
+    // Garbage does not deserialize into a state.
+    let garbage = vec![b'#' as fmi2Byte; size];
+    let mut invalid: fmi2FMUstate = std::ptr::null_mut();
+    assert_eq!(fmi2DeSerializeFMUstate(c, garbage.as_ptr(), size, &mut invalid), fmi2Status_fmi2Error);
+    assert!(invalid.is_null());
+    assert_eq!(fmi2Reset(c), fmi2Status_fmi2OK);
+
+    assert_eq!(fmi2FreeFMUstate(c, &mut restored), fmi2Status_fmi2OK);
+    assert_eq!(fmi2FreeFMUstate(c, &mut fmu_state), fmi2Status_fmi2OK);
+    fmi2FreeInstance(c);
+}
+
+#[test]
+fn test_string_lifetime() {
+    let c = instantiate(fmi2Type_fmi2CoSimulation);
+
+    let first = CString::new("first").unwrap();
+    assert_eq!(fmi2SetString(c, [VR_LABEL].as_ptr(), 1, [first.as_ptr()].as_ptr()), fmi2Status_fmi2OK);
+    drop(first);
+    assert_eq!(fmi2EnterInitializationMode(c), fmi2Status_fmi2OK);
+
+    let mut value: [*const c_char; 1] = [std::ptr::null()];
+    assert_eq!(fmi2GetString(c, [VR_LABEL].as_ptr(), 1, value.as_mut_ptr()), fmi2Status_fmi2OK);
+    let held = value[0];
+
+    // The returned string stays valid until the next call that returns strings, even if the
+    // variable changes in between.
+    let second = CString::new("second").unwrap();
+    assert_eq!(fmi2SetString(c, [VR_LABEL].as_ptr(), 1, [second.as_ptr()].as_ptr()), fmi2Status_fmi2OK);
+    assert_eq!(unsafe { CStr::from_ptr(held) }.to_str().unwrap(), "first");
+
+    assert_eq!(fmi2GetString(c, [VR_LABEL].as_ptr(), 1, value.as_mut_ptr()), fmi2Status_fmi2OK);
+    assert_eq!(unsafe { CStr::from_ptr(value[0]) }.to_str().unwrap(), "second");
+    fmi2FreeInstance(c);
+}
@@ -12,7 +12,8 @@ extern crate num_derive;
 extern crate fmi2;
 
 use fmi2::derive::*;
-use fmi2::{FmiModelStructDerive, FMIErrors};
+use fmi2::logger::{Category, Logger};
+use fmi2::FmiModelStructDerive;
 
 pub const VERSION: &str = "2.0";
 pub const GUID: &str = "{21d9f232-b090-4c79-933f-33da939b5934}";
@@ -21,7 +22,7 @@ const FMI2TRUE: fmi2Boolean = fmi2True as fmi2Boolean;
 const FMI2FALSE: fmi2Boolean = fmi2False as fmi2Boolean;
 
 #[repr(C)]
-#[derive(FmiModelStructDerive)]
+#[derive(FmiModelStructDerive, Default)]
 #[fmi_model(
     description = "How is it going?",
     guid = "{21d9f232-b090-4c79-933f-33da939b5934}"
@@ -73,6 +74,7 @@ pub struct ModelInstance {
     jsynced: fmi2Boolean,
     state: ModelState,
     functions: fmi2CallbackFunctions,
+    logger: Logger,
 
     tolerance: fmi2Real,
     startTime: fmi2Real,
@@ -105,39 +107,25 @@ pub extern "C" fn fmi2GetTypesPlatform() -> *const c_char {
 pub extern "C" fn fmi2SetDebugLogging(
     c: *mut c_void,
     loggingOn: fmi2Boolean,
-    _nCategories: usize,
-    _categories: *const fmi2String,
+    nCategories: usize,
+    categories: *const fmi2String,
 ) -> fmi2Status {
     assert!(
         std::ptr::null() != c as *mut c_void,
-        "fmi2FreeInstance: Null pointer passed"
+        "fmi2SetDebugLogging: Null pointer passed"
     );
 
     let x: &mut ModelInstance = unsafe { &mut *(c as *mut ModelInstance) };
 
-    // https://stackoverflow.com/questions/26117197/create-interface-to-c-function-pointers-in-rust
-    // Create a c-string from a rust string and report it
-
-    match x.functions.logger {
-        Some(f) => {
-            // TODO(cw): Create a wrapper around calling this function (and other callback functions)
-            let category = CString::new("log").unwrap();
-            let message = CString::new("fmi2SetDebugLogging: loggingOn = %d").unwrap();
-            unsafe {
-                f(
-                    c,
-                    x.instanceName,
-                    fmi2Status_fmi2OK,
-                    category.into_raw(),
-                    message.into_raw(),
-                    loggingOn,
-                );
-            }
-        }
-        None => {
-            ();
-        }
+    let categories = unsafe { fmi2::logger::category_names(categories, nCategories) };
+    if let Err(e) = x.logger.set_debug_logging(loggingOn == FMI2TRUE, &categories) {
+        x.logger.log(Category::Error, &format!("fmi2SetDebugLogging: {}", e));
+        return fmi2Status_fmi2Error;
     }
+    x.logger.log(
+        Category::FmiCalls,
+        &format!("fmi2SetDebugLogging: loggingOn = {}", loggingOn),
+    );
 
     match x.state {
         ModelState::Error => fmi2Status_fmi2Error,
@@ -154,7 +142,7 @@ pub extern "C" fn fmi2Instantiate(
     fmuType: fmi2Type,
     fmuGUID: fmi2String,
     _fmuResourceLocation: fmi2String,
-    functions: fmi2CallbackFunctions,
+    functions: *const fmi2CallbackFunctions,
     _visible: fmi2Boolean,
     loggingOn: fmi2Boolean,
 ) -> *mut ModelInstance {
@@ -168,9 +156,20 @@ pub extern "C" fn fmi2Instantiate(
         "fmi2Instantiate: Null pointer passed"
     );
 
+    assert!(
+        std::ptr::null() != functions,
+        "fmi2Instantiate: Null pointer passed"
+    );
+
+    let instance_name = unsafe { CStr::from_ptr(instanceName as *mut c_char) };
+    let logger = Logger::new(&instance_name.to_string_lossy(), Some(unsafe { *functions }), loggingOn == FMI2TRUE);
+
     let guid = unsafe { CStr::from_ptr(fmuGUID as *mut c_char) };
 
-    println!("fmi2Instantiate: GUID = {}", guid.to_str().unwrap());
+    logger.log(
+        Category::FmiCalls,
+        &format!("fmi2Instantiate: GUID = {}", guid.to_string_lossy()),
+    );
     assert!(
         guid.to_str().unwrap() == GUID,
         "fmi2Instantiate: Invalid GUID"
@@ -184,7 +183,8 @@ pub extern "C" fn fmi2Instantiate(
         synced: FMI2FALSE,
         jsynced: FMI2FALSE,
         state: ModelState::Instantiated,
-        functions: functions,
+        functions: unsafe { *functions },
+        logger: logger,
 
         tolerance: 0.0,
         startTime: 0.0,
@@ -198,24 +198,11 @@ pub extern "C" fn fmi2Instantiate(
         gain: 1.0,
     });
 
-    if x.loggingOn == FMI2TRUE {
-        // // https://stackoverflow.com/questions/26117197/create-interface-to-c-function-pointers-in-rust
-        // // Create a c-string from a rust string and report it
-        let category = CString::new("log").unwrap();
-        let message = CString::new("fmi2Instantiate: instanceName = %s").unwrap();
-        unsafe {
-            x.functions.logger.unwrap()(
-                x.as_mut() as *mut ModelInstance as *mut c_void,
-                instanceName,
-                fmi2Status_fmi2OK,
-                category.into_raw(),
-                message.into_raw(),
-                instanceName,
-            );
-        }
-    }
+    x.logger.log(
+        Category::FmiCalls,
+        &format!("fmi2Instantiate: instanceName = {}", x.logger.instance_name()),
+    );
 
-    println!("{:?}", x);
     Box::into_raw(x) as *mut ModelInstance
 }
 
@@ -227,24 +214,10 @@ pub extern "C" fn fmi2FreeInstance(c: fmi2Component) -> () {
     );
 
     let x: &mut ModelInstance = unsafe { &mut *(c as *mut ModelInstance) };
-    println!("{:?}", x);
-
-    // https://stackoverflow.com/questions/26117197/create-interface-to-c-function-pointers-in-rust
-    // Create a c-string from a rust string and report it
-    if x.loggingOn == FMI2TRUE {
-        let category = CString::new("log").unwrap();
-        let message = CString::new("fmi2FreeInstance: instanceName = %s").unwrap();
-        unsafe {
-            x.functions.logger.unwrap()(
-                c,
-                x.instanceName,
-                fmi2Status_fmi2OK,
-                category.into_raw(),
-                message.into_raw(),
-                x.instanceName,
-            );
-        }
-    }
+    x.logger.log(
+        Category::FmiCalls,
+        &format!("fmi2FreeInstance: instanceName = {}", x.logger.instance_name()),
+    );
 
     match x.functions.freeMemory {
         None => {
@@ -719,9 +692,22 @@ pub extern "C" fn fmi2GetStringStatus(
 #[cfg(test)]
 mod sine_fmi_tests {
     use super::*;
+    #[test]
+    fn sine_fmu_model_description() {
+        use fmi2::model_description::ModelDescription;
+
+        let model = ModelDescription::from_xml(include_str!("../Sine.fmu/modelDescription.xml")).unwrap();
+        assert_eq!(model.model_name, "Sine");
+        assert_eq!(model.co_simulation.as_ref().unwrap().model_identifier, "Sine");
+        let names: Vec<&str> = model.model_variables.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["internalTime", "output", "frequency", "gain"]);
+        assert_eq!(model.model_structure.outputs.len(), 2);
+        assert_eq!(ModelDescription::from_xml(&model.to_xml()).unwrap(), model);
+    }
+
     #[test]
     fn print_xml() {
-        println!("{}", Foo::to_model_description_xml());
+        println!("{}", Foo::to_model_description_xml().unwrap());
         panic!("")
     }
 }
\ No newline at end of file